@@ -0,0 +1,51 @@
+#![no_main]
+#![no_std]
+
+//! Toggle the green LED on every press of SW2
+//!
+//! The button is sampled every millisecond from an LPIT delay, and a press is accepted once it has
+//! been seen in 10 samples in a row.
+
+extern crate cortex_m_rt;
+extern crate embedded_hal;
+extern crate s32k144;
+extern crate s32k144evb;
+
+use cortex_m_rt::entry;
+
+use embedded_hal::blocking::delay::DelayMs;
+
+use s32k144evb::board::Board;
+use s32k144evb::{button, led, lpit, pcc};
+
+#[entry]
+fn main() -> ! {
+    let board = Board::take().unwrap();
+    let peripherals = &board.peripherals;
+
+    let pcc_portc = board.pcc.enable_portc().unwrap();
+    let pcc_portd = board.pcc.enable_portd().unwrap();
+
+    let led = led::RgbLed::init(&peripherals.PTD, &peripherals.PORTD, &pcc_portd);
+    led.set(false, false, false);
+
+    let sw2 = button::Button::init(
+        button::Switch::Sw2,
+        &peripherals.PTC,
+        &peripherals.PORTC,
+        &pcc_portc,
+    );
+
+    // `Board::take` runs SOSCDIV2 at 8 MHz
+    let pcc_lpit = board.pcc.enable_lpit(pcc::ClockSource::Soscdiv2).unwrap();
+    let mut tick = lpit::Delay::init(&peripherals.LPIT0, &board.spc, &pcc_lpit).unwrap();
+
+    let mut debounce = button::Debounce::new(10);
+
+    loop {
+        tick.delay_ms(1u32);
+        if debounce.update(sw2.is_pressed()) == Some(true) {
+            led.toggle(led::Channel::Green);
+        }
+    }
+}
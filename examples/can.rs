@@ -12,9 +12,9 @@ use s32k144evb::{can, spc, wdog};
 
 use s32k144evb::pcc::Pcc;
 
-use s32k144evb::can::{CanSettings, ID};
+use s32k144evb::can::CanSettings;
 
-use embedded_types::can::{BaseID, DataFrame};
+use embedded_types::can::DataFrame;
 
 #[entry]
 fn main() -> ! {
@@ -41,26 +41,25 @@ fn main() -> ! {
         spc_config,
     )
     .unwrap();
+    spc.verify().unwrap();
 
     let mut can_settings = CanSettings::default();
     can_settings.self_reception = false;
 
     // Enable and configure the system oscillator
     let pcc = Pcc::init(&peripherals.PCC);
-    let _pcc_can0 = pcc.enable_can0().unwrap();
-    let _pcc_porte = pcc.enable_porte().unwrap();
+    let pcc_can0 = pcc.enable_can0(&spc).unwrap();
+    let pcc_porte = pcc.enable_porte().unwrap();
 
     // Configure the can i/o pins
-    let porte = peripherals.PORTE;
-    porte.pcr4.modify(|_, w| w.mux()._101());
-    porte.pcr5.modify(|_, w| w.mux()._101());
+    can::configure_can0_pins(&peripherals.PORTE, &pcc_porte);
 
-    let can = can::Can::init(&peripherals.CAN0, &spc, &can_settings).unwrap();
+    let can = can::Can::init_clocked(&peripherals.CAN0, &pcc_can0, &can_settings).unwrap();
 
     loop {
         let loop_max = 100000;
         for n in 0..256 {
-            let mut message = DataFrame::new(ID::BaseID(BaseID::new(n as u16)));
+            let mut message = DataFrame::new(can::base_id(n as u16).unwrap());
             message.set_data_length(8);
             for i in 0..8 {
                 message.data_as_mut()[i] = i as u8;
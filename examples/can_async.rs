@@ -0,0 +1,106 @@
+#![no_main]
+#![no_std]
+
+//! Transmit and receive with the `async` CAN driver, shown in loopback mode
+//!
+//! The mailbox interrupt reaches the driver's wakers through a static `CanWaker`. There is no
+//! executor in this crate, so a minimal `block_on` sleeps until the task is woken and polls
+//! again. A frame that doesn't loop back unchanged panics (reported over serial with the default
+//! panic handler).
+//!
+//! Build with `--features async`.
+
+extern crate cortex_m;
+extern crate cortex_m_rt;
+extern crate embedded_types;
+extern crate s32k144;
+extern crate s32k144evb;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use cortex_m_rt::entry;
+
+use embedded_types::can::{CanFrame, DataFrame};
+
+use s32k144::{interrupt, Interrupt};
+
+use s32k144evb::board::Board;
+use s32k144evb::can::{self, CanSettings};
+
+static CAN0_WAKER: can::CanWaker = can::CanWaker::new();
+
+#[interrupt]
+fn CAN0_ORed_0_15_MB() {
+    CAN0_WAKER.on_interrupt(unsafe { &*s32k144::CAN0::ptr() });
+}
+
+#[entry]
+fn main() -> ! {
+    let board = Board::take().unwrap();
+    let mut core = cortex_m::Peripherals::take().unwrap();
+
+    let can_settings = CanSettings::builder()
+        .loopback(true)
+        .self_reception(true)
+        .build()
+        .unwrap();
+
+    let pcc_can0 = board.pcc.enable_can0(&board.spc).unwrap();
+    let mut can =
+        can::Can::init_clocked(&board.peripherals.CAN0, &pcc_can0, &can_settings).unwrap();
+    can.set_waker(&CAN0_WAKER);
+    core.NVIC.enable(Interrupt::CAN0_ORed_0_15_MB);
+
+    let mut counter: u8 = 0;
+    loop {
+        let mut frame = DataFrame::new(can::extended_id(0x1234).unwrap());
+        frame.set_data_length(1);
+        frame.data_as_mut()[0] = counter;
+        let frame: CanFrame = frame.into();
+
+        block_on(can.transmit_async(&frame)).unwrap();
+        assert_eq!(block_on(can.receive_async()).unwrap(), frame);
+
+        counter = counter.wrapping_add(1);
+    }
+}
+
+/// Set by the waker of `block_on`
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Poll `future` to completion, sleeping until it's woken between polls
+fn block_on<F: Future>(mut future: F) -> F::Output {
+    let waker = unsafe { Waker::from_raw(raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    // The future is never moved after being pinned here
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        // With interrupts disabled a wake can't slip in between the check and the sleep, a pending
+        // interrupt still ends the sleep and is handled once they're enabled again
+        cortex_m::interrupt::disable();
+        if !WOKEN.swap(false, Ordering::Relaxed) {
+            cortex_m::asm::wfi();
+        }
+        unsafe { cortex_m::interrupt::enable() };
+    }
+}
+
+fn raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        raw_waker()
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::Relaxed);
+    }
+    fn drop_waker(_: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop_waker);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
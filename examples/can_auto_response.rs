@@ -0,0 +1,67 @@
+#![no_main]
+#![no_std]
+
+//! Answer remote frames in hardware, shown in loopback mode
+//!
+//! The controller is armed to answer remote frames for one ID with a status frame, whose counter
+//! is bumped with `update_auto_response` every round. `Can::request` then sends a remote frame
+//! for that ID, which loops back to the answer mailbox, and waits for the answer the controller
+//! sends by itself. A missing or stale answer panics (reported over serial with the default panic
+//! handler).
+
+extern crate cortex_m_rt;
+extern crate embedded_types;
+extern crate s32k144;
+extern crate s32k144evb;
+
+use cortex_m_rt::entry;
+
+use embedded_types::can::{CanFrame, DataFrame};
+
+use s32k144evb::board::Board;
+use s32k144evb::can::{self, CanSettings};
+
+const STATUS_ID: u16 = 0x123;
+
+fn status_frame(counter: u8) -> CanFrame {
+    let mut frame = DataFrame::new(can::base_id(STATUS_ID).unwrap());
+    frame.set_data_length(2);
+    frame.data_as_mut().copy_from_slice(&[0xa5, counter]);
+    frame.into()
+}
+
+#[entry]
+fn main() -> ! {
+    let board = Board::take().unwrap();
+
+    let can_settings = CanSettings::builder()
+        .loopback(true)
+        .self_reception(true)
+        .individual_masking(true)
+        .build()
+        .unwrap();
+
+    let pcc_can0 = board.pcc.enable_can0(&board.spc).unwrap();
+    let mut can =
+        can::Can::init_clocked(&board.peripherals.CAN0, &pcc_can0, &can_settings).unwrap();
+
+    let mut counter: u8 = 0;
+    can.set_auto_response(&status_frame(counter)).unwrap();
+
+    loop {
+        match can.request(can::base_id(STATUS_ID).unwrap(), 100_000) {
+            Ok(CanFrame::DataFrame(answer)) => assert_eq!(answer.data(), &[0xa5, counter][..]),
+            Ok(CanFrame::RemoteFrame(_)) => panic!("answered with a remote frame"),
+            Err(_) => panic!("no answer to the remote frame"),
+        }
+
+        counter = counter.wrapping_add(1);
+        // Busy while the previous answer is still going out
+        loop {
+            match can.update_auto_response(&status_frame(counter)) {
+                Err(can::CanError::BusyMailboxWriteAttempted) => (),
+                result => break result.unwrap(),
+            }
+        }
+    }
+}
@@ -1,11 +1,16 @@
 //! Integration testing of the CSEc module. Tests the following:
 //! - initializes the CSEc module;
+//! - runs the library's known answer self test;
 //! - loads a plaintext key;
 //! - randomizes 128 bits of data;
 //! - encrypts a byte string to said key, using the randomized bits as initialization vector;
 //! - decrypts the encrypted strings (ensuring the string matches before encryption and after
 //! decryption);
-//! - generates a MAC for a string and verifies it.
+//! - encrypts and decrypts the same string with the pointer format, comparing against the copy
+//! format;
+//! - generates a MAC for a string and verifies it;
+//! - generates and verifies MACs for messages spanning one or more rounds of 7 pages, comparing
+//! against reference values.
 #![no_main]
 #![no_std]
 
@@ -19,21 +24,54 @@ const PLAINKEY: [u8; 16] = [
     0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
 ];
 
+/// Buffers handed to the engine by address must be 32-bit aligned
+#[repr(align(4))]
+struct Aligned([u8; MSG_LEN]);
+
+/// AES-CMAC of the messages `[0, 1, 2, ..]` (wrapping at 256) with `PLAINKEY`, by length in pages
+const LONG_MACS: [(usize, [u8; 16]); 4] = [
+    (
+        7,
+        [
+            0x12, 0x25, 0x9f, 0xfa, 0xa8, 0x5c, 0xe2, 0x84, 0x37, 0x31, 0xf6, 0x55, 0xa0, 0xaf,
+            0x3c, 0x94,
+        ],
+    ),
+    (
+        8,
+        [
+            0x92, 0x22, 0x30, 0xae, 0x80, 0x40, 0x1c, 0x75, 0x1d, 0x43, 0x1c, 0x60, 0x59, 0xf0,
+            0x1d, 0xa8,
+        ],
+    ),
+    (
+        15,
+        [
+            0xaa, 0xf7, 0xfe, 0xd7, 0xe0, 0x88, 0xa7, 0xfa, 0x58, 0x20, 0x1e, 0xbd, 0x06, 0x85,
+            0x24, 0x7e,
+        ],
+    ),
+    (
+        100,
+        [
+            0xcd, 0x37, 0x2d, 0xc7, 0x62, 0xac, 0x19, 0x06, 0xab, 0x26, 0xb5, 0x72, 0xb3, 0x38,
+            0xd3, 0x0d,
+        ],
+    ),
+];
+
 #[entry]
 unsafe fn main() -> ! {
     let p = s32k144::Peripherals::take().unwrap();
 
     // Disable watchdog
-    let wdog_settings = wdog::WatchdogSettings {
-        enable: false,
-        ..Default::default()
-    };
-    let _wdog = wdog::Watchdog::init(&p.WDOG, wdog_settings).unwrap();
+    wdog::Watchdog::disable(&p.WDOG).unwrap();
 
     let mut buffer: [u8; MSG_LEN] = [0; MSG_LEN];
 
     // Initialize CSEc module
     let csec = csec::CSEc::init(p.FTFC, p.CSE_PRAM);
+    csec.self_test().unwrap();
     csec.init_rng().unwrap();
     csec.load_plainkey(&PLAINKEY).unwrap();
 
@@ -46,10 +84,38 @@ unsafe fn main() -> ! {
     csec.decrypt_cbc(&rnd_buf, &mut buffer).unwrap();
     assert!(MSG == &buffer[..]);
 
+    // Encrypt and decrypt `MSG` with the pointer format, the ciphertext must match the copy format
+    let mut plaintext = Aligned([0; MSG_LEN]);
+    let mut ciphertext = Aligned([0; MSG_LEN]);
+    plaintext.0.copy_from_slice(MSG);
+    csec.encrypt_cbc_pointer(&rnd_buf, &plaintext.0, &mut ciphertext.0)
+        .unwrap();
+    csec.encrypt_cbc(&rnd_buf, &mut buffer).unwrap();
+    assert!(ciphertext.0[..] == buffer[..]);
+    csec.decrypt_cbc_pointer(&rnd_buf, &ciphertext.0, &mut plaintext.0)
+        .unwrap();
+    assert!(MSG == &plaintext.0[..]);
+
     // Generate a MAC for `MSG` and verify it
     let cmac = csec.generate_mac(&MSG).unwrap();
     assert!(csec.verify_mac(&MSG, &cmac).unwrap());
 
+    // Generate and verify MACs over several rounds of First/Subsequent commands
+    let mut long_msg: [u8; 100 * 16] = [0; 100 * 16];
+    for (i, byte) in long_msg.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    for &(pages, ref expected) in LONG_MACS.iter() {
+        let msg = &long_msg[..pages * 16];
+        let cmac = csec.generate_mac(msg).unwrap();
+        assert!(&cmac == expected);
+        assert!(csec.verify_mac(msg, &cmac).unwrap());
+
+        let mut wrong = cmac;
+        wrong[15] ^= 1;
+        assert!(!csec.verify_mac(msg, &wrong).unwrap());
+    }
+
     // light green LED
     let pcc = Pcc::init(&p.PCC);
     let pcc_portd = pcc.enable_portd().unwrap();
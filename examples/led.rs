@@ -7,20 +7,15 @@ extern crate s32k144evb;
 
 use cortex_m_rt::entry;
 
-use s32k144evb::{led, wdog};
-
-use s32k144evb::pcc::Pcc;
+use s32k144evb::board::Board;
+use s32k144evb::led;
 
 #[entry]
 fn main() -> ! {
-    let peripherals = s32k144::Peripherals::take().unwrap();
-
-    let mut wdog_settings = wdog::WatchdogSettings::default();
-    wdog_settings.enable = false;
-    let _wdog = wdog::Watchdog::init(&peripherals.WDOG, wdog_settings);
+    let board = Board::take().unwrap();
+    let peripherals = &board.peripherals;
 
-    let pcc = Pcc::init(&peripherals.PCC);
-    let pcc_portd = pcc.enable_portd().unwrap();
+    let pcc_portd = board.pcc.enable_portd().unwrap();
 
     let led = led::RgbLed::init(&peripherals.PTD, &peripherals.PORTD, &pcc_portd);
 
@@ -25,9 +25,7 @@ const APP: () = {
 
     #[init(schedule = [toggle])]
     fn init() {
-        let mut wdog_settings = wdog::WatchdogSettings::default();
-        wdog_settings.enable = false;
-        let _wdog = wdog::Watchdog::init(&device.WDOG, wdog_settings);
+        wdog::Watchdog::disable(&device.WDOG).unwrap();
 
         let pcc = pcc::Pcc::init(&device.PCC);
         let pcc_portd = pcc.enable_portd().unwrap();
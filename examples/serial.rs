@@ -8,10 +8,9 @@ extern crate s32k144evb;
 
 use cortex_m_rt::entry;
 
-use embedded_types::io::Read;
 use embedded_types::io::Write;
 
-use s32k144evb::{spc, wdog};
+use s32k144evb::{console, lpuart, spc, wdog};
 
 use s32k144evb::pcc::{self, Pcc};
 
@@ -19,9 +18,7 @@ use s32k144evb::pcc::{self, Pcc};
 fn main() -> ! {
     let peripherals = s32k144::Peripherals::take().unwrap();
 
-    let mut wdog_settings = wdog::WatchdogSettings::default();
-    wdog_settings.enable = false;
-    let _wdog = wdog::Watchdog::init(&peripherals.WDOG, wdog_settings);
+    wdog::Watchdog::disable(&peripherals.WDOG).unwrap();
 
     let pc_config = spc::Config {
         system_oscillator: spc::SystemOscillatorInput::Crystal(8_000_000),
@@ -36,28 +33,29 @@ fn main() -> ! {
         pc_config,
     )
     .unwrap();
+    spc.verify().unwrap();
 
     let pcc = Pcc::init(&peripherals.PCC);
-    let _pcc_lpuart1 = pcc.enable_lpuart1(pcc::ClockSource::Soscdiv2).unwrap();
-    let _pcc_portc = pcc.enable_portc().unwrap();
-
-    let portc = peripherals.PORTC;
-    portc.pcr6.modify(|_, w| w.mux()._010());
-    portc.pcr7.modify(|_, w| w.mux()._010());
-
-    let mut console = s32k144evb::console::LpuartConsole::init(&peripherals.LPUART1, &spc);
-
-    writeln!(console, "Please write something").unwrap();
-    let mut buf = [0u8; 64];
-    let chars = console.read_until(b'\n', &mut buf).unwrap();
-
-    writeln!(
-        console,
-        "Your wrote: \"{}\"",
-        core::str::from_utf8(&buf[0..chars]).unwrap()
+    let _pcc_lpuart1 = pcc
+        .enable_lpuart1(pcc::ClockSource::Soscdiv2, &spc)
+        .unwrap();
+    let pcc_portc = pcc.enable_portc().unwrap();
+
+    let mut console = console::LpuartConsole::init_with_pins(
+        &peripherals.LPUART1,
+        &spc,
+        &lpuart::Pin::lpuart1_tx_ptc7(&pcc_portc),
+        &lpuart::Pin::lpuart1_rx_ptc6(&pcc_portc),
     )
     .unwrap();
 
+    writeln!(console, "Please write something").unwrap();
+    let mut reader = console::LineReader::<64>::new();
+    match reader.read_line(&mut console) {
+        Ok(line) => writeln!(console, "Your wrote: \"{}\"", line).unwrap(),
+        Err(e) => writeln!(console, "Couldn't read your line: {:?}", e).unwrap(),
+    }
+
     writeln!(
         console,
         "Next a panic will be demonstrated by overflowing an integer"
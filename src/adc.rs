@@ -0,0 +1,189 @@
+//! The Analog-to-Digital Converter (ADC) SW module
+//!
+//! Conversions are either started from software, or armed and started by a hardware trigger.
+//! Hardware triggers come from the PDB or from the TRGMUX (selected in `SIM_ADCOPT`), so the
+//! sampling instant follows e.g. a timer or PWM period without any software jitter.
+//!
+//! Only ADC0 and the first conversion channel (`SC1A`/`RA`) are supported for now.
+
+use bit_field::BitField;
+
+use s32k144;
+
+use crate::pcc;
+
+/// Writing all ones to `SC1[ADCH]` disables the module
+const SC1_ADCH_DISABLED: u32 = 0b11_1111;
+
+/// Configurations for the ADC
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Config {
+    /// Resolution of the conversion result
+    pub resolution: Resolution,
+
+    /// What starts a conversion
+    pub trigger: Trigger,
+
+    /// How a completed conversion is signaled
+    pub completion: Completion,
+}
+
+/// Resolution of the conversion result
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Resolution {
+    /// 8-bit conversion
+    B8,
+
+    /// 10-bit conversion
+    B10,
+
+    /// 12-bit conversion
+    B12,
+}
+
+impl Default for Resolution {
+    fn default() -> Self {
+        Resolution::B12
+    }
+}
+
+/// What starts a conversion
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Trigger {
+    /// A conversion is started when `Adc::start` is called
+    Software,
+
+    /// `Adc::start` arms the channel, the conversion is started by the Programmable Delay Block
+    Pdb,
+
+    /// `Adc::start` arms the channel, the conversion is started by the TRGMUX `ADC0` output
+    ///
+    /// Connect a source to `trgmux::Target::Adc0Tla0` to select what triggers the conversion.
+    Trgmux,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Trigger::Software
+    }
+}
+
+/// How a completed conversion is signaled
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Completion {
+    /// The application polls `Adc::read`
+    Polled,
+
+    /// The `ADC0` interrupt is requested
+    Interrupt,
+
+    /// A DMA request is generated, the result can be read from the `RA` register
+    Dma,
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Completion::Polled
+    }
+}
+
+/// The valid error types for Adc::start()
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The s32k144 has 32 input channels (some of them internal)
+    InvalidChannel,
+}
+
+/// The ADC instance
+pub struct Adc<'a> {
+    adc: &'a s32k144::adc0::RegisterBlock,
+    _pcc_adc0: &'a pcc::Adc0<'a>,
+    config: Config,
+}
+
+impl<'a> Adc<'a> {
+    /// Initializes ADC0 with the given configs
+    ///
+    /// The functional clock selected in `pcc::Pcc::enable_adc0` is used as conversion clock.
+    pub fn init(
+        adc: &'a s32k144::adc0::RegisterBlock,
+        sim: &'a s32k144::sim::RegisterBlock,
+        pcc_adc0: &'a pcc::Adc0<'a>,
+        config: Config,
+    ) -> Self {
+        let mode = match config.resolution {
+            Resolution::B8 => 0b00,
+            Resolution::B12 => 0b01,
+            Resolution::B10 => 0b10,
+        };
+
+        // Disable the module while configuring it
+        adc.sc1a.write(|w| unsafe { w.bits(SC1_ADCH_DISABLED) });
+
+        adc.cfg1
+            .write(|w| unsafe { w.bits(0u32.set_bits(2..4, mode).get_bits(0..32)) });
+
+        let hardware_trigger = config.trigger != Trigger::Software;
+        adc.sc2.write(|w| unsafe {
+            w.bits(
+                0u32.set_bit(6, hardware_trigger)
+                    .set_bit(2, config.completion == Completion::Dma)
+                    .get_bits(0..32),
+            )
+        });
+
+        // ADC0TRGSEL selects between PDB and TRGMUX, and ADC0PRETRGSEL must follow it
+        let (trgsel, pretrgsel) = match config.trigger {
+            Trigger::Software | Trigger::Pdb => (false, 0b00),
+            Trigger::Trgmux => (true, 0b01),
+        };
+        sim.adcopt.modify(|r, w| unsafe {
+            w.bits(
+                r.bits()
+                    .set_bit(0, trgsel)
+                    .set_bits(4..6, pretrgsel)
+                    .get_bits(0..32),
+            )
+        });
+
+        Adc {
+            adc: adc,
+            _pcc_adc0: pcc_adc0,
+            config: config,
+        }
+    }
+
+    /// Start a conversion of `channel`, or arm it for the configured hardware trigger
+    pub fn start(&self, channel: u8) -> Result<(), Error> {
+        if channel >= 32 {
+            return Err(Error::InvalidChannel);
+        }
+
+        let interrupt = self.config.completion == Completion::Interrupt;
+        self.adc.sc1a.write(|w| unsafe {
+            w.bits(
+                0u32.set_bit(6, interrupt)
+                    .set_bits(0..6, channel as u32)
+                    .get_bits(0..32),
+            )
+        });
+        Ok(())
+    }
+
+    /// Return the result of the last conversion if it has completed
+    pub fn read(&self) -> Option<u16> {
+        if self.adc.sc1a.read().bits().get_bit(7) {
+            // Reading the result clears COCO
+            Some(self.adc.ra.read().bits() as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Stop any ongoing conversion and disarm the hardware trigger
+    pub fn stop(&self) {
+        self.adc
+            .sc1a
+            .write(|w| unsafe { w.bits(SC1_ADCH_DISABLED) });
+    }
+}
@@ -0,0 +1,88 @@
+//! The mandatory early boot steps for s32k144evb in one place
+//!
+//! `Board::take` disables the watchdog, sets up the clocks and hands out the peripherals, so an
+//! application can start with
+//!
+//! ```rust
+//! let board = s32k144evb::board::Board::take().unwrap();
+//! let pcc_portd = board.pcc.enable_portd().unwrap();
+//! let led = led::RgbLed::init(&board.peripherals.PTD, &board.peripherals.PORTD, &pcc_portd);
+//! ```
+//!
+//! Applications that need full control can still do these steps themselves with the `wdog`, `spc`
+//! and `pcc` modules.
+
+use s32k144;
+
+use crate::{pcc, spc, wdog};
+
+/// The valid error types for Board::take()
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The peripherals have already been taken
+    AlreadyTaken,
+
+    /// The watchdog couldn't be disabled, `take` must be called within 128 cycles of startup
+    Watchdog(wdog::WatchdogError),
+
+    /// The clocks couldn't be configured
+    Spc(spc::Error),
+}
+
+/// The initialized board
+pub struct Board {
+    /// The watchdog, disabled but with updates allowed
+    pub watchdog: wdog::Watchdog<'static>,
+
+    /// The clocks, configured with the given `spc::Config`
+    pub spc: spc::Spc<'static>,
+
+    /// The peripheral clock controller
+    pub pcc: pcc::Pcc<'static>,
+
+    /// All peripherals, `WDOG`, `SCG`, `SMC`, `PMC` and `PCC` are in use by the drivers above and
+    /// should only be accessed through them
+    pub peripherals: s32k144::Peripherals,
+}
+
+impl Board {
+    /// Take the board with the clock configuration of the examples
+    ///
+    /// The core runs from FIRC (48 MHz) and the 8 MHz crystal is running with `soscdiv2` undivided,
+    /// which is what the LPUART console and CAN expect.
+    pub fn take() -> Result<Self, Error> {
+        Board::take_with_config(spc::Config {
+            system_oscillator: spc::SystemOscillatorInput::Crystal(8_000_000),
+            soscdiv2: spc::SystemOscillatorOutput::Div1,
+            ..Default::default()
+        })
+    }
+
+    /// Take the board, disabling the watchdog and configuring the clocks with `spc_config`
+    pub fn take_with_config(spc_config: spc::Config) -> Result<Self, Error> {
+        let peripherals = s32k144::Peripherals::take().ok_or(Error::AlreadyTaken)?;
+
+        // The peripherals are singletons, so handing out 'static references to the register blocks
+        // owned by `peripherals` doesn't create any new owners
+        let (wdog, scg, smc, pmc, pcc) = unsafe {
+            (
+                &*s32k144::WDOG::ptr(),
+                &*s32k144::SCG::ptr(),
+                &*s32k144::SMC::ptr(),
+                &*s32k144::PMC::ptr(),
+                &*s32k144::PCC::ptr(),
+            )
+        };
+
+        // This must happen first, within 128 cycles of startup
+        let watchdog = wdog::Watchdog::disable(wdog).map_err(Error::Watchdog)?;
+        let spc = spc::Spc::init(scg, smc, pmc, spc_config).map_err(Error::Spc)?;
+
+        Ok(Board {
+            watchdog: watchdog,
+            spc: spc,
+            pcc: pcc::Pcc::init(pcc),
+            peripherals: peripherals,
+        })
+    }
+}
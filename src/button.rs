@@ -0,0 +1,161 @@
+//! The user push-buttons of s32k144evb
+//!
+//! SW2 is on PTC12 and SW3 on PTC13. Both have an external pull-down, so a pin reads high while its
+//! button is pressed.
+//!
+//! ```rust
+//! let pcc_portc = board.pcc.enable_portc().unwrap();
+//! let sw2 = button::Button::init(button::Switch::Sw2, &p.PTC, &p.PORTC, &pcc_portc);
+//! if sw2.is_pressed() {
+//!     led.toggle(led::Channel::Green);
+//! }
+//! ```
+
+use core::convert::Infallible;
+
+use embedded_hal::digital::v2::InputPin;
+use s32k144;
+
+use crate::pcc;
+
+/// One of the user buttons, the discriminant is its pin on port C
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Switch {
+    Sw2 = 12,
+    Sw3 = 13,
+}
+
+/// The edge of the pin signal that raises the port C interrupt
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Edge {
+    /// The button is pressed
+    Rising = 0b1001,
+
+    /// The button is released
+    Falling = 0b1010,
+
+    Either = 0b1011,
+}
+
+/// `PCR[MUX]` set to GPIO, with the passive input filter (`PCR[PFE]`) enabled
+const PCR_GPIO_FILTERED: u32 = (0b001 << 8) | (1 << 4);
+
+pub struct Button<'a> {
+    ptc: &'a s32k144::ptc::RegisterBlock,
+    portc: &'a s32k144::portc::RegisterBlock,
+    _pcc_portc: &'a pcc::PortC<'a>,
+    pin: u32,
+}
+
+impl<'a> Button<'a> {
+    /// Configure the button's pin as a filtered GPIO input, with its interrupt disabled
+    pub fn init(
+        switch: Switch,
+        ptc: &'a s32k144::ptc::RegisterBlock,
+        portc: &'a s32k144::portc::RegisterBlock,
+        pcc_portc: &'a pcc::PortC,
+    ) -> Self {
+        let pin = switch as u32;
+
+        // GPCLR writes its lower half to the lower half of the PCRs whose bit is set in its upper
+        // half. GICLR is the other way around: its upper half is written to the upper half of the
+        // PCRs (which holds IRQC) whose bit is set in its lower half.
+        portc
+            .gpclr
+            .write(|w| unsafe { w.bits((1 << (pin + 16)) | PCR_GPIO_FILTERED) });
+        portc.giclr.write(|w| unsafe { w.bits(1 << pin) });
+        portc.isfr.write(|w| unsafe { w.bits(1 << pin) });
+
+        ptc.pddr
+            .write(|w| unsafe { w.pdd().bits(ptc.pddr.read().bits() & !(1 << pin)) });
+
+        Button {
+            ptc: ptc,
+            portc: portc,
+            _pcc_portc: pcc_portc,
+            pin: pin,
+        }
+    }
+
+    /// Return true while the button is held down, without debouncing
+    pub fn is_pressed(&self) -> bool {
+        self.ptc.pdir.read().pdi().bits() & (1 << self.pin) != 0
+    }
+
+    /// Raise the port C interrupt (`PORTC` in the NVIC) on `edge`
+    ///
+    /// The interrupt is shared with the other pins of port C, check `take_interrupt` in the
+    /// handler.
+    pub fn enable_interrupt(&self, edge: Edge) {
+        self.portc
+            .giclr
+            .write(|w| unsafe { w.bits(((edge as u32) << 16) | (1 << self.pin)) });
+    }
+
+    pub fn disable_interrupt(&self) {
+        self.portc.giclr.write(|w| unsafe { w.bits(1 << self.pin) });
+    }
+
+    /// Return true if the configured edge has occurred since the last call, and clear the flag
+    pub fn take_interrupt(&self) -> bool {
+        let flagged = self.portc.isfr.read().bits() & (1 << self.pin) != 0;
+        if flagged {
+            self.portc.isfr.write(|w| unsafe { w.bits(1 << self.pin) });
+        }
+        flagged
+    }
+}
+
+impl<'a> InputPin for Button<'a> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_pressed())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.is_pressed())
+    }
+}
+
+/// Software debouncing, accepting a new state once it has been sampled `samples` times in a row
+///
+/// Feed it from a periodic tick, e.g. with `samples` 5 at a 1 ms tick a press must last 5 ms.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Debounce {
+    samples: u8,
+    count: u8,
+    pressed: bool,
+}
+
+impl Debounce {
+    pub fn new(samples: u8) -> Self {
+        Debounce {
+            samples: samples,
+            count: 0,
+            pressed: false,
+        }
+    }
+
+    /// Add a sample, returning the new state if it changed
+    pub fn update(&mut self, pressed: bool) -> Option<bool> {
+        if pressed == self.pressed {
+            self.count = 0;
+            return None;
+        }
+
+        self.count += 1;
+        if self.count < self.samples {
+            return None;
+        }
+
+        self.count = 0;
+        self.pressed = pressed;
+        Some(pressed)
+    }
+
+    /// The debounced state
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+}
@@ -1,24 +1,83 @@
 use bit_field::BitField;
 
+use core::cell::Cell;
+use core::ops::Range;
+use core::ptr;
+
 use s32k144;
 use s32k144::can0;
 
-use crate::spc;
+use crate::{dma, pcc, spc};
+
+#[cfg(feature = "async")]
+use crate::waker::{poll_fn, WakerRegistration};
+#[cfg(feature = "async")]
+use core::task::Poll;
+#[cfg(feature = "async")]
+use cortex_m::interrupt::Mutex;
 
 pub use embedded_types::can::{CanFrame, ID};
 
 use embedded_types;
 
-use embedded_types::can::{BaseID, ExtendedDataFrame, ExtendedID};
+use embedded_types::can::{BaseID, DataFrame, ExtendedDataFrame, ExtendedID};
 
 use embedded_types::io::Error as IOError;
 
+use embedded_hal::digital::v2::OutputPin;
+
 const TX_MAILBOXES: usize = 8;
 const RX_MAILBOXES: usize = 8;
 
+/// Pin mux value (ALT5) for CAN0_RX on PTE4
+pub const CAN0_RX_PTE4_ALT: u8 = 0b101;
+
+/// Pin mux value (ALT5) for CAN0_TX on PTE5
+pub const CAN0_TX_PTE5_ALT: u8 = 0b101;
+
+/// With `CTRL2[RFFN] = 0` the RX FIFO and its filter table occupy mailbox 0 to 7
+const RX_FIFO_MAILBOXES: usize = 8;
+
+/// The receive mailboxes with their own masks, `RX14MASK` and `RX15MASK`
+const RX14_MAILBOX: usize = 14;
+const RX15_MAILBOX: usize = 15;
+
+/// Mailboxes are matched before the RX FIFO (`CTRL2[MRP]`)
+const CTRL2_MRP: u32 = 1 << 18;
+
+/// Words per mailbox with 8 byte payloads (`FDCTRL[MBDSR0] = 0`), the only size this driver uses
+const MAILBOX_WORDS: usize = 4;
+
+/// Data bytes in a mailbox of `MAILBOX_WORDS` words
+const MAILBOX_DATA_BYTES: usize = 8;
+
+/// The RX FIFO filter table starts at the first word of mailbox 6
+const RX_FIFO_FILTER_TABLE: usize = 6 * MAILBOX_WORDS;
+
+/// Number of RX FIFO filter table elements with `CTRL2[RFFN] = 0`
+const RX_FIFO_FILTERS: usize = 8;
+
+/// The eDMA major loop counter is 15 bits wide when channel linking is disabled
+const MAX_DMA_FIFO_ENTRIES: usize = 0x7fff;
+
+/// Number of frames the software transmit queue can hold
+pub const TX_QUEUE_CAPACITY: usize = 16;
+
 pub struct Can<'a> {
     register_block: &'a s32k144::can0::RegisterBlock,
-    _spc: &'a spc::Spc<'a>,
+
+    /// Frequency of the clock selected by `settings.clock_source`
+    source_frequency: u32,
+    dma_fifo: Option<DmaFifo<'a>>,
+    tx_queue: TxQueue,
+    settings: CanSettings,
+
+    /// The remote frame ID answered by `auto_response_mailbox`, if it's armed
+    auto_response: Option<ID>,
+
+    /// Where `transmit_async`/`receive_async` register their tasks, see `set_waker`
+    #[cfg(feature = "async")]
+    waker: Option<&'a CanWaker>,
 }
 
 impl<'a> Can<'a> {
@@ -27,196 +86,1500 @@ impl<'a> Can<'a> {
         spc: &'a spc::Spc<'a>,
         settings: &CanSettings,
     ) -> Result<Self, CanError> {
-        let source_frequency = {
-            match settings.clock_source {
-                ClockSource::Sys => spc.core_freq(),
-                ClockSource::Soscdiv2 => {
-                    spc.soscdiv2_freq().ok_or(CanError::ClockSourceDisabled)?
-                }
-            }
+        Self::init_with_freq(can, settings, source_frequency(spc, settings)?)
+    }
+
+    /// Like `init`, with the clock frequencies taken from the PCC guard instead of an `Spc`
+    pub fn init_clocked(
+        can: &'a s32k144::can0::RegisterBlock,
+        pcc_can0: &pcc::Can0,
+        settings: &CanSettings,
+    ) -> Result<Self, CanError> {
+        let frequencies = pcc_can0.frequencies();
+        let source_frequency = match settings.clock_source {
+            ClockSource::Sys => frequencies.core,
+            ClockSource::Soscdiv2 => frequencies.soscdiv2.ok_or(CanError::ClockSourceDisabled)?,
         };
+        Self::init_with_freq(can, settings, source_frequency)
+    }
+
+    /// Like `init`, but with the frequency of the clock selected by `settings.clock_source` given
+    /// directly instead of read from an `Spc`
+    ///
+    /// Useful for bringing up CAN in isolation when the clock is known, e.g. 8 MHz from SOSCDIV2.
+    pub fn init_with_freq(
+        can: &'a s32k144::can0::RegisterBlock,
+        settings: &CanSettings,
+        source_frequency: u32,
+    ) -> Result<Self, CanError> {
+        configure(can, source_frequency, settings, false)?;
+
+        Ok(Can {
+            register_block: can,
+            source_frequency: source_frequency,
+            dma_fifo: None,
+            tx_queue: TxQueue::default(),
+            settings: settings.clone(),
+            auto_response: None,
+            #[cfg(feature = "async")]
+            waker: None,
+        })
+    }
 
-        if source_frequency % settings.can_frequency != 0 {
+    /// Initialize with the RX FIFO streaming received frames into `buffer` through an eDMA channel
+    ///
+    /// Received frames are written to `buffer` in order, wrapping around at the end. `poll` returns
+    /// how many frames are waiting and `receive` reads them out. The buffer holds at most
+    /// `buffer.len() - 1` unread frames; if the application doesn't keep up, unread frames are
+    /// overwritten without notice.
+    ///
+    /// The RX FIFO and its filter table occupy the first 8 mailboxes, so no receive mailboxes are
+    /// available in this mode. The DMAMUX clock must be enabled through `pcc::Pcc::enable_dmamux`.
+    pub fn init_dma_fifo(
+        can: &'a s32k144::can0::RegisterBlock,
+        spc: &'a spc::Spc<'a>,
+        settings: &CanSettings,
+        channel: dma::Channel<'a>,
+        buffer: &'a mut [FifoEntry],
+    ) -> Result<Self, CanError> {
+        if buffer.len() < 2 || buffer.len() > MAX_DMA_FIFO_ENTRIES {
             return Err(CanError::SettingsError);
         }
 
-        if source_frequency < settings.can_frequency * 5 {
+        let source = if can as *const _ == s32k144::CAN0::ptr() {
+            dma::Source::FlexCan0
+        } else if can as *const _ == s32k144::CAN1::ptr() {
+            dma::Source::FlexCan1
+        } else {
+            dma::Source::FlexCan2
+        };
+
+        let source_frequency = source_frequency(spc, settings)?;
+        configure(can, source_frequency, settings, true)?;
+
+        // The eDMA reads the whole FIFO output in mailbox 0, which pops the FIFO
+        unsafe {
+            channel.start_circular(
+                source,
+                &can.embedded_ram[0] as *const _ as u32,
+                core::mem::size_of::<FifoEntry>() as u32,
+                buffer.as_mut_ptr() as u32,
+                buffer.len() as u16,
+            );
+        }
+
+        Ok(Can {
+            register_block: can,
+            source_frequency: source_frequency,
+            dma_fifo: Some(DmaFifo {
+                channel: channel,
+                buffer: buffer,
+                read_index: Cell::new(0),
+            }),
+            tx_queue: TxQueue::default(),
+            settings: settings.clone(),
+            auto_response: None,
+            #[cfg(feature = "async")]
+            waker: None,
+        })
+    }
+
+    /// Return how many received frames are waiting in the DMA buffer
+    ///
+    /// Always 0 if the controller wasn't initialized with `init_dma_fifo`.
+    pub fn poll(&self) -> usize {
+        match self.dma_fifo {
+            Some(ref fifo) => fifo.available(),
+            None => 0,
+        }
+    }
+
+    /// Change the bitrate to `settings.can_frequency` without touching the mailboxes
+    ///
+    /// Only the timing fields of `CTRL1` are reprogrammed, in freeze mode. The timing is computed
+    /// and checked the same way as in `init`. The clock source can't be changed this way, so
+    /// `settings.clock_source` must be the one the controller was initialized with. All other
+    /// settings are ignored.
+    pub fn set_bitrate(&mut self, settings: &CanSettings) -> Result<(), CanError> {
+        let can = self.register_block;
+        if can.ctrl1.read().clksrc().bit() != (settings.clock_source == ClockSource::Sys) {
             return Err(CanError::SettingsError);
         }
 
-        // TODO: check if message_buffer_settings are longer than max MB available
+        let timing = BitTiming::new(self.source_frequency, settings.can_frequency)?;
 
-        let presdiv = (source_frequency / settings.can_frequency) / 25;
-        let tqs = (source_frequency / (presdiv + 1)) / settings.can_frequency;
+        enter_freeze(can);
+        timing.write(can);
+        leave_freeze(can);
 
-        // Table 50-26 in datasheet, can standard compliant settings
-        let (pseg2, rjw) = if tqs >= 8 && tqs < 10 {
-            (1, 1)
-        } else if tqs >= 10 && tqs < 15 {
-            (3, 2)
-        } else if tqs >= 15 && tqs < 20 {
-            (6, 2)
-        } else if tqs >= 20 && tqs < 26 {
-            (7, 3)
+        if timing.is_applied(can) {
+            self.settings.can_frequency = settings.can_frequency;
+            Ok(())
         } else {
-            panic!("there should be between 8 and 25 tqs in an bit");
-        };
+            Err(CanError::ConfigurationFailed)
+        }
+    }
 
-        let pseg1 = ((tqs - (pseg2 + 1)) / 2) - 1;
-        let propseg = tqs - (pseg2 + 1) - (pseg1 + 1) - 2;
+    /// Enable or disable reception of this node's own frames (`MCR[SRXDIS]`) without reinitializing
+    ///
+    /// The bit is changed in freeze mode and read back, `settings().self_reception` is updated
+    /// once it has been applied.
+    pub fn set_self_reception(&mut self, self_reception: bool) -> Result<(), CanError> {
+        let can = self.register_block;
+
+        enter_freeze(can);
+        can.mcr.modify(|_, w| w.srxdis().bit(!self_reception));
+        leave_freeze(can);
+
+        if can.mcr.read().srxdis().bit() == !self_reception {
+            self.settings.self_reception = self_reception;
+            Ok(())
+        } else {
+            Err(CanError::ConfigurationFailed)
+        }
+    }
+
+    /// Switch listen only mode (`CTRL1[LOM]`) on or off without reinitializing
+    ///
+    /// The mode can only be changed in freeze mode, so the controller briefly leaves the bus, but
+    /// the mailboxes and filters are kept. Frames already pending in the transmit mailboxes stay
+    /// pending and are sent once listen only mode is left, new ones are refused with
+    /// `IOError::Other` meanwhile. `settings().listen_only` is updated once the change has been
+    /// read back. Fails with `SettingsError` when turning it on in loopback mode.
+    pub fn set_listen_only(&mut self, listen_only: bool) -> Result<(), CanError> {
+        let can = self.register_block;
+        if listen_only && self.settings.loopback_mode {
+            return Err(CanError::SettingsError);
+        }
+
+        enter_freeze(can);
+        can.ctrl1.modify(|_, w| w.lom().bit(listen_only));
+        leave_freeze(can);
 
-        reset(can);
+        if can.ctrl1.read().lom().bit() == listen_only {
+            self.settings.listen_only = listen_only;
+            Ok(())
+        } else {
+            Err(CanError::ConfigurationFailed)
+        }
+    }
+
+    /// Enable or disable the TX and RX warning interrupts without reinitializing
+    ///
+    /// The warning flags are enabled by `MCR[WRNEN]`, which can only be written in freeze mode, so
+    /// the controller briefly leaves the bus. The interrupt masks `CTRL1[TWRNMSK]` and
+    /// `CTRL1[RWRNMSK]` follow, and `settings().warning_interrupt` is updated once the change has
+    /// been read back.
+    pub fn set_warning_interrupts(&mut self, enable: bool) -> Result<(), CanError> {
+        let can = self.register_block;
 
-        // first set clock source
+        enter_freeze(can);
+        can.mcr.modify(|_, w| w.wrnen().bit(enable));
+        leave_freeze(can);
         can.ctrl1
-            .modify(|_, w| w.clksrc().bit(settings.clock_source == ClockSource::Sys));
+            .modify(|_, w| w.twrnmsk().bit(enable).rwrnmsk().bit(enable));
+
+        if can.mcr.read().wrnen().bit() == enable {
+            self.settings.warning_interrupt = enable;
+            Ok(())
+        } else {
+            Err(CanError::ConfigurationFailed)
+        }
+    }
+
+    /// Run `f` with raw access to the registers while the controller is in freeze mode
+    ///
+    /// Freeze mode is entered before `f` is called and left after it returns, whichever way it
+    /// returns, so the handshake is never left half-done. The controller doesn't take part in bus
+    /// traffic in the meantime. Changes made by `f` that the driver doesn't know about (e.g. to the
+    /// mailbox layout or `MCR[RFEN]`) can break it, this is an escape hatch for settings the driver
+    /// doesn't cover.
+    pub fn with_freeze<R, F: FnOnce(&can0::RegisterBlock) -> R>(&self, f: F) -> R {
+        let can = self.register_block;
 
-        enable(can);
         enter_freeze(can);
+        let result = f(can);
+        leave_freeze(can);
 
-        can.mcr.modify(|_, w| {
-            w.rfen()
-                .bit(false)
-                .srxdis()
-                .bit(!settings.self_reception)
-                .irmq()
-                .bit(settings.individual_masking)
-                .aen()
-                .bit(true)
-                .dma()
-                .bit(false);
-            unsafe { w.maxmb().bits((RX_MAILBOXES + TX_MAILBOXES) as u8 - 1) };
-            w
-        });
+        result
+    }
 
-        can.ctrl1.modify(|_, w| unsafe {
-            w.presdiv()
-                .bits(presdiv as u8)
-                .pseg1()
-                .bits(pseg1 as u8)
-                .pseg2()
-                .bits(pseg2 as u8)
-                .propseg()
-                .bits(propseg as u8)
-                .rjw()
-                .bits(rjw as u8)
-                .lpb()
-                .bit(settings.loopback_mode)
-        });
+    /// Reset the transfer state without touching the bit timing
+    ///
+    /// Pending transmissions are inactivated, the receive mailboxes are emptied and rearmed, their
+    /// interrupt flags are cleared and the software transmit queue is emptied. Useful to start
+    /// clean after e.g. a bus off recovery.
+    ///
+    /// Mailboxes 14 and 15 are only emptied, keeping the filters set with `set_rx14_mask` and
+    /// `set_rx15_mask`, and the `set_auto_response` mailbox is left armed. The RX FIFO used by
+    /// `init_dma_fifo` is left alone, including its flags.
+    pub fn clear_all_mailboxes(&self) {
+        let can = self.register_block;
+        let mut flags = 0u32;
+
+        for mb in self.tx_mailboxes() {
+            inactivate_mailbox(can, mb);
+            flags.set_bit(mb, true);
+        }
 
-        // set filter mask to accept all
-        // TODO: Make better logic for setting filters
-        can.rxmgmask.write(unsafe { |w| w.bits(0) });
+        if self.dma_fifo.is_none() {
+            let filter_frame = CanFrame::from(ExtendedDataFrame::new(ExtendedID::new(0)));
+            for mb in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
+                if mb != RX14_MAILBOX && mb != RX15_MAILBOX {
+                    inactivate_mailbox(can, mb);
+                    write_mailbox(can, &MailboxHeader::default_receive(), &filter_frame, mb)
+                        .unwrap();
+                }
+                flags.set_bit(mb, true);
+            }
+        }
 
-        /*
-        • Initialize the Message Buffers
-        • The Control and Status word of all Message Buffers must be initialized
-        • If Rx FIFO was enabled, the ID filter table must be initialized
-        • Other entries in each Message Buffer should be initialized as required
-         */
+        while self.tx_queue.pop().is_some() {}
 
-        let filter_frame = CanFrame::from(ExtendedDataFrame::new(ExtendedID::new(0))); // TODO: set filters better then on extended data frames
+        can.iflag1.write(|w| unsafe { w.bits(flags) });
+    }
 
-        for mb in 0..TX_MAILBOXES {
-            inactivate_mailbox(can, mb as usize);
-            write_mailbox(
-                can,
-                &MailboxHeader::default_transmit(),
-                &filter_frame,
-                mb as usize,
-            )
-            .unwrap();
+    /// Read the key controller registers, e.g. for printing when the bus misbehaves
+    pub fn debug_snapshot(&self) -> CanDebug {
+        let can = self.register_block;
+        CanDebug {
+            mcr: can.mcr.read().bits(),
+            ctrl1: can.ctrl1.read().bits(),
+            esr1: can.esr1.read().bits(),
+            ecr: can.ecr.read().bits(),
+            iflag1: can.iflag1.read().bits(),
+            imask1: can.imask1.read().bits(),
         }
+    }
 
-        for mb in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
-            inactivate_mailbox(can, mb as usize);
+    /// The settings the controller is running with
+    ///
+    /// This is the `CanSettings` given to `init`, with `can_frequency` updated by `set_bitrate`.
+    pub fn settings(&self) -> &CanSettings {
+        &self.settings
+    }
+
+    /// Disable the controller and release the register block
+    ///
+    /// The controller is left in freeze mode and disabled, and the DMA channel (if any) is stopped
+    /// and released. `init` can then be run again, e.g. with a new bit timing.
+    pub fn free(self) -> &'a s32k144::can0::RegisterBlock {
+        let Can {
+            register_block: can,
+            dma_fifo,
+            ..
+        } = self;
+
+        // Stop the eDMA before the FIFO goes away underneath it
+        drop(dma_fifo);
+
+        enter_freeze(can);
+        disable(can);
+        can
+    }
+
+    /// The mailboxes used for transmission, they are moved past the RX FIFO when it's enabled
+    ///
+    /// The last one is left out while it's armed by `set_auto_response`.
+    fn tx_mailboxes(&self) -> Range<usize> {
+        let first = if self.dma_fifo.is_some() {
+            RX_FIFO_MAILBOXES
+        } else {
+            0
+        };
+        let reserved = if self.auto_response.is_some() { 1 } else { 0 };
+        first..first + TX_MAILBOXES - reserved
+    }
+
+    /// The mailbox armed by `set_auto_response`, the last transmit mailbox
+    ///
+    /// Remote frames are matched against the mailboxes from the lowest number up, so the answer
+    /// has to come before the receive mailboxes, which accept every ID.
+    fn auto_response_mailbox(&self) -> usize {
+        let first = if self.dma_fifo.is_some() {
+            RX_FIFO_MAILBOXES
+        } else {
+            0
+        };
+        first + TX_MAILBOXES - 1
+    }
+
+    /// Does not attempt to swap frames if all mailboxes are full, not suitable for frames
+    /// that need to live up to some timing requirements, as priority inversion might be unavoidable.
+    pub fn transmit_quick(&self, frame: &CanFrame) -> Result<(), IOError> {
+        self.check_tx_allowed()?;
+        self.transmit_any_mailbox(frame, 0, None).map(|_| ())
+    }
+
+    /// Like `transmit_quick`, but with a local transmit priority from 0 (highest) to 7 (lowest)
+    ///
+    /// The priority only has an effect when `CanSettings::local_priority` is enabled, see its
+    /// documentation for how it interacts with the ID.
+    pub fn transmit_prioritized(&self, frame: &CanFrame, priority: u8) -> Result<(), IOError> {
+        self.check_tx_allowed()?;
+        self.transmit_any_mailbox(frame, priority, None).map(|_| ())
+    }
+
+    /// Like `transmit_quick`, but with the DLC field set to `dlc` regardless of the frame's length
+    ///
+    /// For conformance testing, e.g. a DLC of 8 with fewer data bytes (the rest are sent as 0), or
+    /// the values 9 to 15 which mean 8 bytes on classic frames. Fails with `IOError::Other` if
+    /// `dlc` is above 15.
+    pub fn transmit_with_dlc(&self, frame: &CanFrame, dlc: u8) -> Result<(), IOError> {
+        if dlc > 15 {
+            return Err(IOError::Other);
+        }
+        self.check_tx_allowed()?;
+        self.transmit_any_mailbox(frame, 0, Some(dlc)).map(|_| ())
+    }
+
+    /// The number of transmit mailboxes a frame can be written to right now
+    ///
+    /// Counts the mailboxes that are inactive or whose transmission has completed, the same ones
+    /// the transmit functions would use. Nothing is modified, so the count is only a lookahead:
+    /// the software queue (`service_tx`) or an interrupt handler may take mailboxes meanwhile.
+    pub fn free_tx_mailboxes(&self) -> usize {
+        let can = self.register_block;
+        let completed = can.iflag1.read().bits();
+        self.tx_mailboxes()
+            .filter(|&mb| {
+                completed.get_bit(mb)
+                    || read_mailbox_code(can, mb)
+                        == MessageBufferCode::Transmit(TransmitBufferState::Inactive)
+            })
+            .count()
+    }
+
+    /// The mailbox interrupt flags (`IFLAG1`), bit `n` for mailbox `n`
+    ///
+    /// A flag is set when a transmit mailbox completed or a receive mailbox got a frame, whether or
+    /// not its interrupt is enabled. Reading them all at once lets an interrupt handler service
+    /// every mailbox that needs it and acknowledge them together with `clear_flags`.
+    pub fn pending_flags(&self) -> u32 {
+        self.register_block.iflag1.read().bits()
+    }
+
+    /// Acknowledge the mailbox interrupt flags set in `mask` (write 1 to clear)
+    ///
+    /// Flags not in `mask` are left alone. Acknowledge a receive mailbox only after reading its
+    /// frame, otherwise the flag of a frame that arrived meanwhile is lost; `receive` does both.
+    /// With `init_dma_fifo` the RX FIFO flags (mailboxes 0 to 7) are never cleared, acknowledging
+    /// the frames available flag would pop a frame from under the eDMA.
+    pub fn clear_flags(&self, mask: u32) {
+        let mask = if self.dma_fifo.is_some() {
+            mask & !((1 << RX_FIFO_MAILBOXES) - 1)
+        } else {
+            mask
+        };
+        self.register_block
+            .iflag1
+            .write(|w| unsafe { w.bits(mask) });
+    }
+
+    /// The transmit error counter (`ECR[TXERRCNT]`)
+    pub fn tx_error_count(&self) -> u8 {
+        self.register_block.ecr.read().txerrcnt().bits()
+    }
+
+    /// Return false while transmissions are refused, in listen only mode or while the transmit
+    /// error counter is above the `CanSettings::tx_error_backoff` threshold
+    ///
+    /// The transmit functions report a refusal as `IOError::Other`, like an invalid frame, so check
+    /// this to tell the two apart.
+    pub fn can_transmit(&self) -> bool {
+        if self.settings.listen_only {
+            return false;
+        }
+        match self.settings.tx_error_backoff {
+            Some(threshold) => self.tx_error_count() <= threshold,
+            None => true,
+        }
+    }
+
+    /// Refuse to transmit with `IOError::Other` unless `can_transmit`
+    fn check_tx_allowed(&self) -> Result<(), IOError> {
+        if self.can_transmit() {
+            Ok(())
+        } else {
+            Err(IOError::Other)
+        }
+    }
+
+    /// Write `frame` to the first free transmit mailbox and return the mailbox number
+    fn transmit_any_mailbox(
+        &self,
+        frame: &CanFrame,
+        priority: u8,
+        dlc: Option<u8>,
+    ) -> Result<usize, IOError> {
+        if !id_in_range(frame.id()) {
+            return Err(IOError::Other);
+        }
+
+        let mut header = MailboxHeader::default_transmit();
+        header.code = MessageBufferCode::Transmit(TransmitBufferState::DataRemote);
+        header.priority = priority.get_bits(0..3);
+        header.dlc = dlc;
+
+        for i in self.tx_mailboxes() {
+            if reclaim_tx_mailbox(self.register_block, i) {
+                match write_mailbox(self.register_block, &header, frame, i) {
+                    Ok(()) => return Ok(i),
+                    Err(_) => (),
+                }
+            }
+        }
+        Err(IOError::BufferExhausted)
+    }
+
+    /// Check that `frame` comes back through the internal loopback within `tries` polls
+    ///
+    /// Loopback and self reception are enabled for the duration of the test, the bus is not
+    /// touched. Frames received during the test are discarded. Loopback and self reception are
+    /// restored to their previous state afterwards.
+    ///
+    /// Together with `external_ack` this tells a working controller on a dead bus
+    /// (`loopback_roundtrip` succeeds, `external_ack` fails) apart from a working bus (both
+    /// succeed).
+    pub fn loopback_roundtrip(&self, frame: &CanFrame, tries: u32) -> bool {
+        let (loopback, self_reception) = self.enter_test_mode(true, true);
+
+        let mut echoed = false;
+        if self.transmit_any_mailbox(frame, 0, None).is_ok() {
+            for _ in 0..tries {
+                if let Ok(received) = self.receive() {
+                    if u32::from(received.id()) == u32::from(frame.id()) {
+                        echoed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.enter_test_mode(loopback, self_reception);
+        echoed
+    }
+
+    /// Check that `frame` is acknowledged by another node on the bus within `tries` polls
+    ///
+    /// Loopback and self reception are disabled for the duration of the test, and restored to their
+    /// previous state afterwards. If no node acknowledges the frame it is aborted.
+    pub fn external_ack(&self, frame: &CanFrame, tries: u32) -> bool {
+        let (loopback, self_reception) = self.enter_test_mode(false, false);
+
+        let mut acknowledged = false;
+        if let Ok(mailbox) = self.transmit_any_mailbox(frame, 0, None) {
+            for _ in 0..tries {
+                // The flag is only set when the frame was transmitted, which requires an ACK
+                if self.register_block.iflag1.read().bits().get_bit(mailbox) {
+                    acknowledged = true;
+                    break;
+                }
+            }
+
+            if !acknowledged {
+                abort_mailbox(self.register_block, mailbox);
+            }
+        }
+
+        self.enter_test_mode(loopback, self_reception);
+        acknowledged
+    }
+
+    /// Set loopback and self reception, returns their previous state
+    fn enter_test_mode(&self, loopback: bool, self_reception: bool) -> (bool, bool) {
+        let can = self.register_block;
+        let previous = (can.ctrl1.read().lpb().bit(), !can.mcr.read().srxdis().bit());
+
+        enter_freeze(can);
+        can.ctrl1.modify(|_, w| w.lpb().bit(loopback));
+        can.mcr.modify(|_, w| w.srxdis().bit(!self_reception));
+        leave_freeze(can);
+
+        previous
+    }
+
+    /// If there are no free Mailboxes, the frame with lowest priority will be aborted and returned upon success
+    ///
+    /// Frames with an ID out of range (see `base_id`/`extended_id`) fail with `IOError::Other`.
+    pub fn transmit(&self, frame: &CanFrame) -> Result<Option<CanFrame>, IOError> {
+        self.check_tx_allowed()?;
+        if !id_in_range(frame.id()) {
+            return Err(IOError::Other);
+        }
+
+        let mut highest_id = 0;
+        let mut mailbox_number = usize::max_value();
+
+        let mut transmit_header = MailboxHeader::default_transmit();
+        transmit_header.code = MessageBufferCode::Transmit(TransmitBufferState::DataRemote);
+
+        for i in self.tx_mailboxes() {
+            if reclaim_tx_mailbox(self.register_block, i) {
+                write_mailbox(self.register_block, &transmit_header, frame, i).unwrap();
+                return Ok(None);
+            }
+
+            let (header, old_frame) = read_mailbox(self.register_block, i);
+            match header.code {
+                MessageBufferCode::Transmit(TransmitBufferState::DataRemote) => {
+                    if u32::from(old_frame.id()) > highest_id {
+                        highest_id = u32::from(old_frame.id());
+                        mailbox_number = i;
+                    }
+                }
+                // The transmission completed after the mailbox was checked, it will be picked up
+                // next time
+                _ => (),
+            }
+        }
+
+        if highest_id > u32::from(frame.id()) {
+            let aborted_frame = abort_mailbox(self.register_block, mailbox_number);
+            write_mailbox(self.register_block, &transmit_header, frame, mailbox_number).unwrap();
+            Ok(aborted_frame)
+        } else {
+            Err(IOError::BufferExhausted)
+        }
+    }
+
+    /// Transmit a remote frame for `id` and wait for the data frame answering it
+    ///
+    /// After sending a remote frame the transmit mailbox turns into a receive mailbox for the same
+    /// ID, which captures the answer. That mailbox is polled at most `tries` times; if no answer
+    /// arrives by then, `IOError::BufferExhausted` is returned. Either way the mailbox is handed
+    /// back to the transmit path before returning. With `init_dma_fifo` the RX FIFO is matched
+    /// first, so the answer is looked for there too, and other frames read from the FIFO while
+    /// waiting are discarded.
+    pub fn request(&self, id: ID, tries: u32) -> Result<CanFrame, IOError> {
+        let can = self.register_block;
+        let is_extended = match id {
+            ID::ExtendedID(_) => true,
+            ID::BaseID(_) => false,
+        };
+        let raw_id = u32::from(id);
+        let answers = |frame: &CanFrame| match *frame {
+            CanFrame::DataFrame(_) => {
+                let same_kind = match frame.id() {
+                    ID::ExtendedID(_) => is_extended,
+                    ID::BaseID(_) => !is_extended,
+                };
+                same_kind && u32::from(frame.id()) == raw_id
+            }
+            CanFrame::RemoteFrame(_) => false,
+        };
+
+        self.check_tx_allowed()?;
+        let remote_frame = CanFrame::from(embedded_types::can::RemoteFrame::new(id));
+        let mailbox = self.transmit_any_mailbox(&remote_frame, 0, None)?;
+
+        let mut answer = None;
+        for _ in 0..tries {
+            match read_mailbox_code(can, mailbox) {
+                MessageBufferCode::Receive(ReceiveBufferCode {
+                    state: ReceiveBufferState::Full,
+                    busy: false,
+                })
+                | MessageBufferCode::Receive(ReceiveBufferCode {
+                    state: ReceiveBufferState::Overrun,
+                    busy: false,
+                }) => {
+                    let (_header, frame) = read_mailbox(can, mailbox);
+                    if answers(&frame) {
+                        answer = Some(frame);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+
+            if let Some(ref fifo) = self.dma_fifo {
+                if let Some(frame) = fifo.pop().map(|entry| entry.frame()) {
+                    if answers(&frame) {
+                        answer = Some(frame);
+                        break;
+                    }
+                }
+            }
+        }
+
+        release_request_mailbox(can, mailbox);
+        answer.ok_or(IOError::BufferExhausted)
+    }
+
+    /// Receive the next frame from the DMA buffer together with its time stamp
+    ///
+    /// The time stamp is the 16-bit free running timer captured at the start of the identifier
+    /// field. The timer counts nominal bit times on the bus (e.g. 2 us per tick at 500 kbit/s) and
+    /// wraps around every 65536 bits, so frames should be read out well within that time to be
+    /// ordered correctly.
+    ///
+    /// Always `BufferExhausted` if the controller wasn't initialized with `init_dma_fifo`.
+    pub fn receive_fifo(&self) -> Result<(u16, CanFrame), IOError> {
+        match self.dma_fifo {
+            Some(ref fifo) => fifo
+                .pop()
+                .map(|entry| (entry.time_stamp(), entry.frame()))
+                .ok_or(IOError::BufferExhausted),
+            None => Err(IOError::BufferExhausted),
+        }
+    }
+
+    /// Program the RX FIFO filter table, so only matching frames are received
+    ///
+    /// The table is written in format A (one full ID per element). It has room for
+    /// `RX_FIFO_FILTERS` elements in the mailboxes reserved for the RX FIFO, unused elements repeat
+    /// the first filter. An empty `filters` accepts every frame, as after `init_dma_fifo`. Fails
+    /// with `SettingsError` if there are too many filters or the controller wasn't initialized with
+    /// `init_dma_fifo`.
+    ///
+    /// The controller is in freeze mode while the table is written, frames arriving meanwhile are
+    /// lost.
+    pub fn set_fifo_filters(&self, filters: &[FifoFilter]) -> Result<(), CanError> {
+        if self.dma_fifo.is_none() || filters.len() > RX_FIFO_FILTERS {
+            return Err(CanError::SettingsError);
+        }
+
+        let can = self.register_block;
+        let mask = if filters.is_empty() {
+            0
+        } else {
+            FIFO_FILTER_MASK
+        };
+
+        enter_freeze(can);
+        for i in 0..RX_FIFO_FILTERS {
+            let element = filters
+                .get(i)
+                .or_else(|| filters.first())
+                .map_or(0, FifoFilter::element);
+            can.embedded_ram[RX_FIFO_FILTER_TABLE + i].write(|w| unsafe { w.bits(element) });
+
+            // With individual masking each element has its own mask instead of the global one
+            can.rximr[i].write(|w| unsafe { w.bits(mask) });
+        }
+        can.rxfgmask.write(|w| unsafe { w.bits(mask) });
+        leave_freeze(can);
+
+        Ok(())
+    }
+
+    /// Set the receive mask of a single mailbox, used instead of `RXMGMASK` with individual masking
+    ///
+    /// A set bit in `mask` means that ID bit must match the mailbox's ID, the other bits are don't
+    /// care. All masks are 0 (accept everything) after `init`. The mailbox number is absolute, the
+    /// first `RX_FIFO_MAILBOXES` masks apply to the RX FIFO filter elements when it's enabled.
+    ///
+    /// Fails with `SettingsError` if `settings.individual_masking` is off or the mailbox isn't in
+    /// use (`MCR[MAXMB]`), and with `IdOutOfRange` if the mask has bits beyond the ID.
+    ///
+    /// The controller is in freeze mode while the mask is written, frames arriving meanwhile are
+    /// lost.
+    pub fn set_individual_mask(&self, mailbox: usize, mask: IdMask) -> Result<(), CanError> {
+        let can = self.register_block;
+        let mailboxes = usize::from(can.mcr.read().maxmb().bits()) + 1;
+        if !self.settings.individual_masking || mailbox >= mailboxes.min(can.rximr.len()) {
+            return Err(CanError::SettingsError);
+        }
+        let bits = mask.bits().ok_or(CanError::IdOutOfRange)?;
+
+        self.with_freeze(|can| can.rximr[mailbox].write(|w| unsafe { w.bits(bits) }));
+        Ok(())
+    }
+
+    /// Dedicate mailbox 14 to frames matching `id` in the bits set in `mask` (`RX14MASK`)
+    ///
+    /// Without individual masking mailboxes 14 and 15 have their own masks, while the other receive
+    /// mailboxes use the global mask, which accepts every frame. As receive mailboxes are matched
+    /// from the lowest number, a frame also accepted by a lower mailbox only ends up here while
+    /// those are full.
+    ///
+    /// Fails with `SettingsError` if `settings.individual_masking` is on, if the controller was
+    /// initialized with `init_dma_fifo` (mailbox 14 transmits then) or if `id` and `mask` are of
+    /// different kinds, and with `IdOutOfRange` if either has bits beyond the ID. The controller
+    /// is in freeze mode meanwhile, frames arriving then are lost.
+    pub fn set_rx14_mask(&self, id: ID, mask: IdMask) -> Result<(), CanError> {
+        self.set_special_mask(RX14_MAILBOX, id, mask)
+    }
+
+    /// Like `set_rx14_mask`, for mailbox 15 (`RX15MASK`)
+    pub fn set_rx15_mask(&self, id: ID, mask: IdMask) -> Result<(), CanError> {
+        self.set_special_mask(RX15_MAILBOX, id, mask)
+    }
+
+    fn set_special_mask(&self, mailbox: usize, id: ID, mask: IdMask) -> Result<(), CanError> {
+        let kinds_match = match (id, mask) {
+            (ID::BaseID(_), IdMask::Base(_)) | (ID::ExtendedID(_), IdMask::Extended(_)) => true,
+            _ => false,
+        };
+        if self.settings.individual_masking || self.dma_fifo.is_some() || !kinds_match {
+            return Err(CanError::SettingsError);
+        }
+        if !id_in_range(id) {
+            return Err(CanError::IdOutOfRange);
+        }
+        let bits = mask.bits().ok_or(CanError::IdOutOfRange)?;
+
+        let filter_frame = CanFrame::from(DataFrame::new(id));
+        self.with_freeze(|can| {
+            if mailbox == RX14_MAILBOX {
+                can.rx14mask.write(|w| unsafe { w.bits(bits) });
+            } else {
+                can.rx15mask.write(|w| unsafe { w.bits(bits) });
+            }
+            inactivate_mailbox(can, mailbox);
             write_mailbox(
                 can,
                 &MailboxHeader::default_receive(),
                 &filter_frame,
-                mb as usize,
+                mailbox,
             )
-            .unwrap();
+        })
+    }
+
+    /// Answer remote frames with the ID of `frame` by sending `frame`, without involving the CPU
+    ///
+    /// The last transmit mailbox is armed with the answer (`CODE` = `RANSWER`) and isn't used for
+    /// transmission anymore, leaving 7 transmit mailboxes. When a matching remote frame is
+    /// received, the controller transmits the answer by itself and rearms the mailbox, so the node
+    /// answers even while the CPU is busy. Calling this again replaces the ID and the answer, use
+    /// `update_auto_response` to change only the data. `examples/can_auto_response.rs` shows it in
+    /// loopback mode.
+    ///
+    /// Received frames go to the lowest numbered free mailbox that matches, and that's the answer
+    /// mailbox, below the receive mailboxes. With `init_dma_fifo` the mailboxes are set to be
+    /// matched before the RX FIFO (`CTRL2[MRP]`), the answer mailbox is the only one receiving. It
+    /// is matched with all ID bits through its individual mask, the global mask accepts every ID
+    /// and would have it answer every remote frame.
+    ///
+    /// Fails with `SettingsError` if `settings.individual_masking` is off or `frame` is a remote
+    /// frame, with `BusyMailboxWriteAttempted` if a frame is still pending in the last transmit
+    /// mailbox, and with `IdOutOfRange` if the ID is out of range. The controller is in freeze mode
+    /// while the mailbox is set up, frames arriving meanwhile are lost.
+    pub fn set_auto_response(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+        let can = self.register_block;
+        let mailbox = self.auto_response_mailbox();
+        let id = frame.id();
+        let is_data_frame = match *frame {
+            CanFrame::DataFrame(_) => true,
+            CanFrame::RemoteFrame(_) => false,
+        };
+        if !self.settings.individual_masking || !is_data_frame {
+            return Err(CanError::SettingsError);
         }
+        if !id_in_range(id) {
+            return Err(CanError::IdOutOfRange);
+        }
+        let mask = match id {
+            ID::BaseID(_) => IdMask::Base(MAX_BASE_ID),
+            ID::ExtendedID(_) => IdMask::Extended(MAX_EXTENDED_ID),
+        };
+        let bits = mask.bits().ok_or(CanError::IdOutOfRange)?;
+        if self.auto_response.is_none() && !reclaim_tx_mailbox(can, mailbox) {
+            return Err(CanError::BusyMailboxWriteAttempted);
+        }
+
+        let dma_fifo = self.dma_fifo.is_some();
+        self.with_freeze(|can| {
+            if dma_fifo {
+                can.ctrl2
+                    .modify(|r, w| unsafe { w.bits(r.bits() | CTRL2_MRP) });
+            }
+            can.rximr[mailbox].write(|w| unsafe { w.bits(bits) });
+            inactivate_mailbox(can, mailbox);
+            write_mailbox(can, &auto_response_header(), frame, mailbox)
+        })?;
+        self.auto_response = Some(id);
+        Ok(())
+    }
+
+    /// Replace the data sent by the mailbox armed with `set_auto_response`
+    ///
+    /// The ID stays the same and the controller stays on the bus. The mailbox is inactive for the
+    /// few writes it takes, a remote frame arriving in that window isn't answered. Fails with
+    /// `SettingsError` if no answer is armed or `frame` is a remote frame or has another ID, and
+    /// with `BusyMailboxWriteAttempted` while the previous answer is being transmitted, in which
+    /// case the call can be repeated.
+    pub fn update_auto_response(&self, frame: &CanFrame) -> Result<(), CanError> {
+        let can = self.register_block;
+        let same_id = self
+            .auto_response
+            .map_or(false, |id| match (id, frame.id()) {
+                (ID::BaseID(_), ID::BaseID(_)) | (ID::ExtendedID(_), ID::ExtendedID(_)) => {
+                    u32::from(id) == u32::from(frame.id())
+                }
+                _ => false,
+            });
+        let is_data_frame = match *frame {
+            CanFrame::DataFrame(_) => true,
+            CanFrame::RemoteFrame(_) => false,
+        };
+        if !same_id || !is_data_frame {
+            return Err(CanError::SettingsError);
+        }
+        let mailbox = self.auto_response_mailbox();
+        if read_mailbox_code(can, mailbox)
+            == MessageBufferCode::Transmit(TransmitBufferState::Tanswer)
+        {
+            return Err(CanError::BusyMailboxWriteAttempted);
+        }
+
+        inactivate_mailbox(can, mailbox);
+        write_mailbox(can, &auto_response_header(), frame, mailbox)
+    }
 
-        // clear all interrupt flags so data wont dangle
-        can.iflag1.write(|w| unsafe { w.bits(0xffff_ffff) });
+    /// Put `frame` in the software transmit queue
+    ///
+    /// Queued frames are moved into the transmit mailboxes by `service_tx`, so the application can
+    /// send bursts larger than the number of mailboxes.
+    pub fn enqueue(&self, frame: CanFrame) -> Result<(), CanError> {
+        if !id_in_range(frame.id()) {
+            return Err(CanError::IdOutOfRange);
+        }
+        self.tx_queue.push(frame).map_err(|_| CanError::QueueFull)
+    }
+
+    /// Move queued frames into free transmit mailboxes, highest priority (lowest ID) first
+    ///
+    /// Call this from the transmit complete interrupt or the main loop. Returns the number of
+    /// frames moved out of the queue.
+    pub fn service_tx(&self) -> usize {
+        let mut moved = 0;
+        while let Some(frame) = self.tx_queue.pop() {
+            if self.transmit_quick(&frame).is_err() {
+                // All mailboxes are busy, try again next time
+                let _ = self.tx_queue.push(frame);
+                break;
+            }
+            moved += 1;
+        }
+        moved
+    }
+
+    /// Read every pending receive mailbox into `out`, returning how many were read
+    ///
+    /// The pending mailboxes are found with a single read of `IFLAG1`, so a batch of frames can be
+    /// collected in one critical section. Reading stops early when `out` is full, the remaining
+    /// frames are left for the next call. Always 0 if the controller was initialized with
+    /// `init_dma_fifo`, use `receive_fifo` instead.
+    pub fn drain(&self, out: &mut [(MailboxHeader, CanFrame)]) -> usize {
+        if self.dma_fifo.is_some() {
+            return 0;
+        }
+
+        let pending = self.register_block.iflag1.read().bits();
+        let mut count = 0;
+        for mb in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
+            if count == out.len() {
+                break;
+            }
+            if pending.get_bit(mb) {
+                out[count] = read_mailbox(self.register_block, mb);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Like `receive`, but frames for which `predicate` returns false are discarded
+    ///
+    /// Rejected frames are read out and acknowledged like accepted ones, so their mailboxes are
+    /// free again. Returns `BufferExhausted` once no accepted frame is waiting.
+    pub fn receive_filtered<F: FnMut(&CanFrame) -> bool>(
+        &self,
+        mut predicate: F,
+    ) -> Result<CanFrame, IOError> {
+        loop {
+            let frame = self.receive()?;
+            if predicate(&frame) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// The ID of the frame waiting in receive mailbox `mailbox`, without consuming it
+    ///
+    /// The interrupt flag is left set, so the frame is still returned by `receive` or
+    /// `receive_group(&[mailbox])` later. `None` if the mailbox isn't a receive mailbox or holds
+    /// no unread frame, and always with `init_dma_fifo`.
+    ///
+    /// Reading the mailbox locks it, and the lock is released again before returning: the
+    /// controller holds only one lock, released by reading any other mailbox or the free running
+    /// timer, so it couldn't be kept until the frame is read anyway, and while it's held frames for
+    /// this mailbox wait in the serial message buffer or are lost. Once unlocked, a newer frame
+    /// accepted by the mailbox overwrites the peeked one (`CODE` = `OVERRUN`), so check the ID of
+    /// the frame that is finally read when that matters.
+    pub fn peek_id(&self, mailbox: usize) -> Option<ID> {
+        let can = self.register_block;
+        if self.dma_fifo.is_some()
+            || !(TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES)).contains(&mailbox)
+            || !can.iflag1.read().bits().get_bit(mailbox)
+        {
+            return None;
+        }
+        Some(read_mailbox_id(can, mailbox))
+    }
+
+    /// Read the oldest pending frame among `mailboxes`, by receive time stamp
+    ///
+    /// Mailboxes sharing a filter are filled in turn by the controller, reading them through this
+    /// function makes them act as a small receive ring that keeps frames in arrival order. Mailbox
+    /// numbers are absolute, entries that aren't receive mailboxes are ignored. Always `None` if
+    /// the controller was initialized with `init_dma_fifo`.
+    ///
+    /// The time stamps are compared relative to the free running timer, so the order is only right
+    /// for frames received less than one timer period (65536 bit times) ago.
+    pub fn receive_group(&self, mailboxes: &[usize]) -> Option<CanFrame> {
+        if self.dma_fifo.is_some() {
+            return None;
+        }
+
+        let pending = self.register_block.iflag1.read().bits();
+        let now = self.register_block.timer.read().bits() as u16;
+        mailboxes
+            .iter()
+            .cloned()
+            .filter(|&mb| {
+                (TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES)).contains(&mb) && pending.get_bit(mb)
+            })
+            .max_by_key(|&mb| now.wrapping_sub(read_mailbox_time_stamp(self.register_block, mb)))
+            .map(|mb| read_mailbox(self.register_block, mb).1)
+    }
+
+    pub fn receive(&self) -> Result<CanFrame, IOError> {
+        if let Some(ref fifo) = self.dma_fifo {
+            return fifo
+                .pop()
+                .map(|entry| entry.frame())
+                .ok_or(IOError::BufferExhausted);
+        }
+
+        for i in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
+            let new_message = self.register_block.iflag1.read().bits().get_bit(i);
+            if new_message {
+                let (_header, frame) = read_mailbox(self.register_block, i);
+                return Ok(frame);
+            }
+        }
+        Err(IOError::BufferExhausted)
+    }
+}
+
+/// Forwards frames received on one controller to another, e.g. bridging a private bus to the
+/// vehicle bus
+///
+/// `route` is called with every received frame and returns the frame to send on the other bus,
+/// possibly with a new ID, or `None` to drop it. Forwarding in both directions takes two gateways.
+///
+/// ```rust
+/// let mut gateway = can::Gateway::new(&private_can, &vehicle_can, |frame| {
+///     match u32::from(frame.id()) {
+///         0x100..=0x1ff => Some(frame),
+///         _ => None,
+///     }
+/// });
+/// loop {
+///     gateway.pump();
+/// }
+/// ```
+pub struct Gateway<'c, 'a, F> {
+    from: &'c Can<'a>,
+    to: &'c Can<'a>,
+    route: F,
+    pending: Option<CanFrame>,
+    dropped: u32,
+}
+
+impl<'c, 'a, F: FnMut(CanFrame) -> Option<CanFrame>> Gateway<'c, 'a, F> {
+    pub fn new(from: &'c Can<'a>, to: &'c Can<'a>, route: F) -> Self {
+        Gateway {
+            from: from,
+            to: to,
+            route: route,
+            pending: None,
+            dropped: 0,
+        }
+    }
+
+    /// Forward everything that can be forwarded right now, returning how many frames were sent
+    ///
+    /// When all transmit mailboxes of the destination are busy, the routed frame is held back and
+    /// retried first on the next call, and nothing more is received meanwhile. Frames then wait in
+    /// the source's receive mailboxes, which overrun if the destination bus stays congested. Call
+    /// this in a loop or from the source's receive interrupt.
+    pub fn pump(&mut self) -> usize {
+        let mut forwarded = 0;
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => match self.from.receive() {
+                    Ok(frame) => match (self.route)(frame) {
+                        Some(frame) => frame,
+                        None => continue,
+                    },
+                    Err(_) => return forwarded,
+                },
+            };
+
+            match self.to.transmit_quick(&frame) {
+                Ok(()) => forwarded += 1,
+                Err(IOError::BufferExhausted) => {
+                    self.pending = Some(frame);
+                    return forwarded;
+                }
+                // Out of range IDs from `route`, or a degraded destination bus
+                Err(_) => self.dropped = self.dropped.wrapping_add(1),
+            }
+        }
+    }
+
+    /// Number of routed frames the destination refused, see `CanSettings::tx_error_backoff`
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+/// Drive a transceiver's standby (STB) pin, high puts the transceiver in standby
+///
+/// Many transceivers stay off the bus until STB is driven low, so call this with `false` after
+/// `Can::init`, and with `true` before putting the controller in a low-power mode. The pin is any
+/// embedded-hal output, e.g. a GPIO wired to STB.
+pub fn set_transceiver_standby<P: OutputPin>(pin: &mut P, standby: bool) -> Result<(), P::Error> {
+    if standby {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
+}
+
+/// The largest 11-bit standard ID
+pub const MAX_BASE_ID: u16 = 0x7ff;
+
+/// The largest 29-bit extended ID
+pub const MAX_EXTENDED_ID: u32 = 0x1fff_ffff;
+
+/// A standard ID, or `IdOutOfRange` if `id` doesn't fit in 11 bits
+pub fn base_id(id: u16) -> Result<ID, CanError> {
+    if id > MAX_BASE_ID {
+        Err(CanError::IdOutOfRange)
+    } else {
+        Ok(ID::BaseID(BaseID::new(id)))
+    }
+}
+
+/// An extended ID, or `IdOutOfRange` if `id` doesn't fit in 29 bits
+pub fn extended_id(id: u32) -> Result<ID, CanError> {
+    if id > MAX_EXTENDED_ID {
+        Err(CanError::IdOutOfRange)
+    } else {
+        Ok(ID::ExtendedID(ExtendedID::new(id)))
+    }
+}
+
+/// Return false for IDs that would be truncated when written to a mailbox
+fn id_in_range(id: ID) -> bool {
+    match id {
+        ID::BaseID(_) => u32::from(id) <= u32::from(MAX_BASE_ID),
+        ID::ExtendedID(_) => u32::from(id) <= MAX_EXTENDED_ID,
+    }
+}
+
+/// The size of the largest frame encoded by `frame_to_bytes`, a data frame with 8 bytes
+pub const MAX_FRAME_BYTES: usize = 14;
+
+const FRAME_FLAG_EXTENDED: u8 = 1 << 0;
+const FRAME_FLAG_REMOTE: u8 = 1 << 1;
+
+/// The valid error types for `frame_from_bytes`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameBytesError {
+    /// The bytes end before the frame does
+    TooShort,
+
+    /// Unknown bits are set in the flags byte
+    InvalidFlags,
+
+    /// The data length is above 8, or not 0 for a remote frame
+    InvalidLength,
+
+    /// The ID doesn't fit in 11 or 29 bits
+    IdOutOfRange,
+}
+
+/// Encode `frame` into `buf`, returning the number of bytes written
+///
+/// The layout is
+///
+/// | Bytes      | Content                                                     |
+/// |------------|-------------------------------------------------------------|
+/// | 0..4       | ID, big endian                                              |
+/// | 4          | Flags, bit 0 for an extended ID and bit 1 for remote frames |
+/// | 5          | Data length, 0 to 8 (always 0 for remote frames)            |
+/// | 6..6 + len | Data                                                        |
+///
+/// so a frame takes 6 to `MAX_FRAME_BYTES` bytes. Panics if `buf` is too short for `frame`.
+pub fn frame_to_bytes(frame: &CanFrame, buf: &mut [u8]) -> usize {
+    let id = frame.id();
+    let mut flags = match id {
+        ID::BaseID(_) => 0,
+        ID::ExtendedID(_) => FRAME_FLAG_EXTENDED,
+    };
+    let data: &[u8] = match *frame {
+        CanFrame::DataFrame(ref data_frame) => data_frame.data(),
+        CanFrame::RemoteFrame(_) => {
+            flags |= FRAME_FLAG_REMOTE;
+            &[]
+        }
+    };
+
+    let len = 6 + data.len();
+    buf[0..4].copy_from_slice(&u32::from(id).to_be_bytes());
+    buf[4] = flags;
+    buf[5] = data.len() as u8;
+    buf[6..len].copy_from_slice(data);
+    len
+}
+
+/// Decode a frame written by `frame_to_bytes`, returning it and the number of bytes it took
+///
+/// Bytes after the frame are ignored, so a buffer of concatenated frames can be decoded one
+/// frame at a time.
+pub fn frame_from_bytes(bytes: &[u8]) -> Result<(CanFrame, usize), FrameBytesError> {
+    if bytes.len() < 6 {
+        return Err(FrameBytesError::TooShort);
+    }
+
+    let raw_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let flags = bytes[4];
+    let data_length = bytes[5] as usize;
+    if flags & !(FRAME_FLAG_EXTENDED | FRAME_FLAG_REMOTE) != 0 {
+        return Err(FrameBytesError::InvalidFlags);
+    }
+    let remote = flags & FRAME_FLAG_REMOTE != 0;
+    if data_length > 8 || (remote && data_length != 0) {
+        return Err(FrameBytesError::InvalidLength);
+    }
+    let len = 6 + data_length;
+    if bytes.len() < len {
+        return Err(FrameBytesError::TooShort);
+    }
+
+    let id = if flags & FRAME_FLAG_EXTENDED != 0 {
+        extended_id(raw_id)
+    } else if raw_id <= u32::from(MAX_BASE_ID) {
+        base_id(raw_id as u16)
+    } else {
+        Err(CanError::IdOutOfRange)
+    }
+    .map_err(|_| FrameBytesError::IdOutOfRange)?;
+
+    let frame = if remote {
+        CanFrame::from(embedded_types::can::RemoteFrame::new(id))
+    } else {
+        let mut frame = DataFrame::new(id);
+        frame.set_data_length(data_length);
+        frame.data_as_mut().copy_from_slice(&bytes[6..len]);
+        CanFrame::from(frame)
+    };
+    Ok((frame, len))
+}
+
+#[cfg(feature = "async")]
+impl<'a> Can<'a> {
+    /// Use `waker` to wake the tasks waiting in `transmit_async`/`receive_async`
+    ///
+    /// The CAN interrupt handler must call `waker.on_interrupt`. Without a waker the futures are
+    /// polled again right away instead of waiting for an interrupt.
+    pub fn set_waker(&mut self, waker: &'a CanWaker) {
+        self.waker = Some(waker);
+    }
+
+    /// Transmit `frame` like `transmit_quick`, waiting for a free mailbox instead of failing
+    pub async fn transmit_async(&self, frame: &CanFrame) -> Result<(), IOError> {
+        poll_fn(|cx| match self.transmit_quick(frame) {
+            Err(IOError::BufferExhausted) => {
+                match self.waker {
+                    Some(waker) => {
+                        waker.tx.register(cx.waker());
+                        self.enable_mailbox_interrupts(waker, self.tx_mailboxes());
+                    }
+                    None => cx.waker().wake_by_ref(),
+                }
+
+                // A mailbox may have completed before the interrupt was enabled
+                match self.transmit_quick(frame) {
+                    Err(IOError::BufferExhausted) => Poll::Pending,
+                    result => Poll::Ready(result),
+                }
+            }
+            result => Poll::Ready(result),
+        })
+        .await
+    }
+
+    /// Receive a frame like `receive`, waiting for one to arrive
+    ///
+    /// With `init_dma_fifo` there are no receive mailbox interrupts, the result of `receive` is
+    /// returned right away.
+    pub async fn receive_async(&self) -> Result<CanFrame, IOError> {
+        let rx_mailboxes = TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES);
+        poll_fn(|cx| match self.receive() {
+            Err(IOError::BufferExhausted) if self.dma_fifo.is_none() => {
+                match self.waker {
+                    Some(waker) => {
+                        waker.rx.register(cx.waker());
+                        self.enable_mailbox_interrupts(waker, rx_mailboxes.clone());
+                    }
+                    None => cx.waker().wake_by_ref(),
+                }
+
+                // A frame may have arrived before the interrupt was enabled
+                match self.receive() {
+                    Err(IOError::BufferExhausted) => Poll::Pending,
+                    result => Poll::Ready(result),
+                }
+            }
+            result => Poll::Ready(result),
+        })
+        .await
+    }
+
+    /// Enable the interrupts of `mailboxes`, recording in `waker` the ones that weren't enabled
+    /// already so `on_interrupt` masks only those
+    fn enable_mailbox_interrupts(&self, waker: &CanWaker, mailboxes: Range<usize>) {
+        let mut mask = 0u32;
+        for mb in mailboxes {
+            mask.set_bit(mb, true);
+        }
+        cortex_m::interrupt::free(|cs| {
+            let enabled = waker.enabled.borrow(cs);
+            let imask1 = self.register_block.imask1.read().bits();
+            enabled.set(enabled.get() | (mask & !imask1));
+            self.register_block
+                .imask1
+                .write(|w| unsafe { w.bits(imask1 | mask) });
+        });
+    }
+}
+
+/// The tasks waiting in `Can::transmit_async`/`Can::receive_async`, shared with the interrupt
+/// handler
+///
+/// It's `Sync` and has a `const` constructor, so it can be a static the handler reaches:
+///
+/// ```rust
+/// static CAN0_WAKER: can::CanWaker = can::CanWaker::new();
+///
+/// #[interrupt]
+/// fn CAN0_ORed_0_15_MB() {
+///     CAN0_WAKER.on_interrupt(unsafe { &*s32k144::CAN0::ptr() });
+/// }
+/// ```
+///
+/// and is handed to the driver with `Can::set_waker(&CAN0_WAKER)`.
+#[cfg(feature = "async")]
+pub struct CanWaker {
+    tx: WakerRegistration,
+    rx: WakerRegistration,
+
+    /// The `IMASK1` bits enabled by the futures, the other ones belong to the application
+    enabled: Mutex<Cell<u32>>,
+}
+
+#[cfg(feature = "async")]
+impl CanWaker {
+    pub const fn new() -> Self {
+        CanWaker {
+            tx: WakerRegistration::new(),
+            rx: WakerRegistration::new(),
+            enabled: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Mask the mailbox interrupts enabled by the futures and wake the tasks waiting on them
+    ///
+    /// Call this from the interrupt handler for mailboxes 0 to 15 of `can`. Mailbox interrupts
+    /// enabled by the application are left enabled, and the flags are left set, they are handled by
+    /// the woken tasks.
+    pub fn on_interrupt(&self, can: &s32k144::can0::RegisterBlock) {
+        cortex_m::interrupt::free(|cs| {
+            let enabled = self.enabled.borrow(cs);
+            can.imask1
+                .modify(|r, w| unsafe { w.bits(r.bits() & !enabled.get()) });
+            enabled.set(0);
+        });
+        self.tx.wake();
+        self.rx.wake();
+    }
+}
+
+/// Mux CAN0 to PTE4 (RX) and PTE5 (TX), these are connected to the CAN transceiver on s32k144evb
+pub fn configure_can0_pins(porte: &s32k144::porte::RegisterBlock, _pcc_porte: &pcc::PortE) {
+    porte.pcr4.modify(|_, w| w.mux().bits(CAN0_RX_PTE4_ALT));
+    porte.pcr5.modify(|_, w| w.mux().bits(CAN0_TX_PTE5_ALT));
+}
+
+/// An RX FIFO filter table element, see `Can::set_fifo_filters`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FifoFilter {
+    /// The ID to accept, a `BaseID` only matches standard frames and an `ExtendedID` only matches
+    /// extended frames
+    pub id: ID,
+
+    /// Accept remote frames with this ID instead of data frames
+    pub remote_frame: bool,
+}
+
+/// Compare all of RTR, IDE and the ID of a format A filter table element
+const FIFO_FILTER_MASK: u32 = 0xffff_fffe;
+
+impl FifoFilter {
+    pub fn new(id: ID, remote_frame: bool) -> Self {
+        FifoFilter {
+            id: id,
+            remote_frame: remote_frame,
+        }
+    }
 
-        leave_freeze(can);
+    /// The filter as a format A table element
+    fn element(&self) -> u32 {
+        let mut element = 0u32;
+        element.set_bit(31, self.remote_frame);
+        match self.id {
+            ID::ExtendedID(_) => {
+                element.set_bit(30, true);
+                element.set_bits(1..30, u32::from(self.id));
+            }
+            ID::BaseID(_) => {
+                element.set_bits(19..30, u32::from(self.id));
+            }
+        }
+        element
+    }
+}
 
-        // Make some acceptance test to see if the configurations have been applied
+/// A received frame as laid out in the RX FIFO output
+///
+/// This is the element type of the buffer given to `Can::init_dma_fifo`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FifoEntry {
+    control_status: u32,
+    id: u32,
+    data: [u32; 2],
+}
 
-        return Ok(Can {
-            register_block: can,
-            _spc: spc,
-        });
+impl FifoEntry {
+    /// The free running timer value captured when the frame was received
+    fn time_stamp(&self) -> u16 {
+        self.control_status.get_bits(0..16) as u16
     }
 
-    /// Does not attempt to swap frames if all mailboxes are full, not suitable for frames
-    /// that need to live up to some timing requirements, as priority inversion might be unavoidable.
-    pub fn transmit_quick(&self, frame: &CanFrame) -> Result<(), IOError> {
-        let mut header = MailboxHeader::default_transmit();
-        header.code = MessageBufferCode::Transmit(TransmitBufferState::DataRemote);
+    fn frame(&self) -> CanFrame {
+        let id = if self.control_status.get_bit(21) {
+            ID::ExtendedID(ExtendedID::new(self.id.get_bits(0..29)))
+        } else {
+            ID::BaseID(BaseID::new(self.id.get_bits(18..29) as u16))
+        };
 
-        for i in 0..TX_MAILBOXES {
-            if read_mailbox_code(self.register_block, i)
-                == MessageBufferCode::Transmit(TransmitBufferState::Inactive)
-            {
-                match write_mailbox(self.register_block, &header, frame, i) {
-                    Ok(()) => return Ok(()),
-                    Err(_) => (),
-                }
+        if self.control_status.get_bit(20) {
+            CanFrame::from(embedded_types::can::RemoteFrame::new(id))
+        } else {
+            // DLC values above 8 still mean 8 bytes for classic CAN frames
+            let dlc = core::cmp::min(self.control_status.get_bits(16..20) as usize, 8);
+            let mut frame = embedded_types::can::DataFrame::new(id);
+            frame.set_data_length(dlc);
+            for i in 0..dlc {
+                frame.data_as_mut()[i] =
+                    self.data[i / 4].get_bits((32 - 8 * (1 + i % 4))..32 - 8 * (i % 4)) as u8;
             }
+            CanFrame::from(frame)
         }
-        Err(IOError::BufferExhausted)
     }
+}
 
-    /// If there are no free Mailboxes, the frame with lowest priority will be aborted and returned upon success
-    pub fn transmit(&self, frame: &CanFrame) -> Result<Option<CanFrame>, IOError> {
-        let mut highest_id = 0;
-        let mut mailbox_number = usize::max_value();
+/// The receive side of a controller initialized with `Can::init_dma_fifo`
+struct DmaFifo<'a> {
+    channel: dma::Channel<'a>,
+    buffer: &'a mut [FifoEntry],
+    read_index: Cell<usize>,
+}
 
-        let mut transmit_header = MailboxHeader::default_transmit();
-        transmit_header.code = MessageBufferCode::Transmit(TransmitBufferState::DataRemote);
+impl<'a> DmaFifo<'a> {
+    fn available(&self) -> usize {
+        let write_index = self.channel.completed_minor_loops() as usize;
+        (write_index + self.buffer.len() - self.read_index.get()) % self.buffer.len()
+    }
 
-        for i in 0..TX_MAILBOXES {
-            let (header, old_frame) = read_mailbox(self.register_block, i);
-            match header.code {
-                MessageBufferCode::Transmit(TransmitBufferState::Inactive) => {
-                    write_mailbox(self.register_block, &transmit_header, frame, i).unwrap();
-                    return Ok(None);
-                }
-                MessageBufferCode::Transmit(TransmitBufferState::DataRemote) => {
-                    if u32::from(old_frame.id()) > highest_id {
-                        highest_id = u32::from(frame.id());
-                        mailbox_number = i;
-                    }
-                }
-                _ => unreachable!(),
-            }
+    fn pop(&self) -> Option<FifoEntry> {
+        if self.available() == 0 {
+            return None;
         }
 
-        if highest_id > u32::from(frame.id()) {
-            let aborted_frame = abort_mailbox(self.register_block, mailbox_number);
-            write_mailbox(self.register_block, &transmit_header, frame, mailbox_number).unwrap();
-            Ok(aborted_frame)
-        } else {
-            Err(IOError::BufferExhausted)
+        let index = self.read_index.get();
+        let entry = unsafe { ptr::read_volatile(&self.buffer[index]) };
+        self.read_index.set((index + 1) % self.buffer.len());
+        Some(entry)
+    }
+}
+
+/// Fixed capacity queue of frames waiting for a transmit mailbox
+///
+/// Frames leave in ID order, and frames with the same ID in the order they were pushed, which
+/// multi-frame protocols rely on.
+#[derive(Default)]
+struct TxQueue {
+    /// The frames, each with the sequence number it was pushed with
+    slots: [Cell<Option<(u32, CanFrame)>>; TX_QUEUE_CAPACITY],
+
+    /// The sequence number of the next frame pushed
+    next_sequence: Cell<u32>,
+}
+
+impl TxQueue {
+    /// Store `frame` in a free slot, returns the frame back if the queue is full
+    fn push(&self, frame: CanFrame) -> Result<(), CanFrame> {
+        for slot in self.slots.iter() {
+            match slot.take() {
+                None => {
+                    let sequence = self.next_sequence.get();
+                    self.next_sequence.set(sequence.wrapping_add(1));
+                    slot.set(Some((sequence, frame)));
+                    return Ok(());
+                }
+                occupied => slot.set(occupied),
+            }
         }
+        Err(frame)
     }
 
-    pub fn receive(&self) -> Result<CanFrame, IOError> {
-        for i in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
-            let new_message = self.register_block.iflag1.read().bits().get_bit(i);
-            if new_message {
-                let (_header, frame) = read_mailbox(self.register_block, i);
-                return Ok(frame);
+    /// Remove and return the frame with the lowest ID, the oldest one if several share it
+    fn pop(&self) -> Option<CanFrame> {
+        // Sequence numbers are compared by their age, so the wrap around at `u32::MAX` is harmless
+        let next_sequence = self.next_sequence.get();
+        let mut highest_priority: Option<(usize, u32, u32)> = None;
+        for (i, slot) in self.slots.iter().enumerate() {
+            let entry = slot.take();
+            if let Some(&(sequence, ref frame)) = entry.as_ref() {
+                let id = u32::from(frame.id());
+                let age = next_sequence.wrapping_sub(sequence);
+                let better = highest_priority.map_or(true, |(_, lowest_id, oldest_age)| {
+                    id < lowest_id || (id == lowest_id && age > oldest_age)
+                });
+                if better {
+                    highest_priority = Some((i, id, age));
+                }
             }
+            slot.set(entry);
         }
-        Err(IOError::BufferExhausted)
+
+        highest_priority
+            .and_then(|(i, _, _)| self.slots[i].take())
+            .map(|(_, frame)| frame)
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CanSettings {
     /// When asserted, this bit enables the generation of the TWRNINT and RWRNINT flags in the Error and
     /// Status Register 1 (ESR1). If WRNEN is negated, the TWRNINT and RWRNINT flags will always be zero,
@@ -242,14 +1605,80 @@ pub struct CanSettings {
     /// transmitted message as a message received from a remote node.
     pub loopback_mode: bool,
 
+    /// Only listen to the bus (`CTRL1[LOM]`), e.g. for a sniffer that mustn't disturb it
+    ///
+    /// The controller receives but never drives the bus, so it sends neither frames nor
+    /// acknowledgements nor error frames. The transmit functions fail with `IOError::Other`, see
+    /// also `Can::set_listen_only`.
+    pub listen_only: bool,
+
     /// This bit selects the clock source to the CAN Protocol Engine (PE) to be either the peripheral clock or the
     /// oscillator clock. The selected clock is the one fed to the prescaler to generate the Serial Clock (Sclock). In
     /// order to guarantee reliable operation
     pub clock_source: ClockSource,
 
+    /// Refuse to transmit while the transmit error counter is above this threshold
+    ///
+    /// The transmit functions then return `IOError::Other` instead of queuing the frame, so the
+    /// application can back off before the node is driven into bus off (at 256). The counter
+    /// decreases again with every successful transmission, see `Can::tx_error_count`. `None`
+    /// disables the check.
+    pub tx_error_backoff: Option<u8>,
+
+    /// Enable the local transmit priority (`MCR[LPRIOEN]`)
+    ///
+    /// When several transmit mailboxes are pending, the controller picks the one to send next by
+    /// comparing the local priority (prepended to the ID) and then the ID itself, lowest value
+    /// first. The local priority never goes out on the bus, so it only decides between this node's
+    /// own frames; arbitration against other nodes is still done on the ID alone. Without this
+    /// setting the priority given to `Can::transmit_prioritized` is ignored.
+    pub local_priority: bool,
+
+    /// The bitrate on the CAN bus in bit/s
+    ///
+    /// The standard rates are available as `Bitrate` presets, e.g. `Bitrate::Kbps500.into()`. Other
+    /// rates are accepted as long as the source clock can be divided down to exactly this rate with
+    /// 8 to 25 time quanta per bit.
     pub can_frequency: u32,
 }
 
+/// The standard CAN bitrates
+///
+/// All presets are achievable from both an 8 MHz source (e.g. `soscdiv2` from the board's crystal)
+/// and a 40 MHz source:
+///
+/// | Preset  | 8 MHz source          | 40 MHz source          |
+/// |---------|-----------------------|------------------------|
+/// | Kbps125 | prescaler 4, 16 tq    | prescaler 16, 20 tq    |
+/// | Kbps250 | prescaler 2, 16 tq    | prescaler 8, 20 tq     |
+/// | Kbps500 | prescaler 1, 16 tq    | prescaler 4, 20 tq     |
+/// | Mbps1   | prescaler 1, 8 tq     | prescaler 2, 20 tq     |
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Bitrate {
+    /// 125 kbit/s
+    Kbps125,
+
+    /// 250 kbit/s
+    Kbps250,
+
+    /// 500 kbit/s
+    Kbps500,
+
+    /// 1 Mbit/s
+    Mbps1,
+}
+
+impl From<Bitrate> for u32 {
+    fn from(bitrate: Bitrate) -> u32 {
+        match bitrate {
+            Bitrate::Kbps125 => 125_000,
+            Bitrate::Kbps250 => 250_000,
+            Bitrate::Kbps500 => 500_000,
+            Bitrate::Mbps1 => 1_000_000,
+        }
+    }
+}
+
 impl Default for CanSettings {
     fn default() -> Self {
         CanSettings {
@@ -257,21 +1686,117 @@ impl Default for CanSettings {
             self_reception: true,
             individual_masking: false,
             loopback_mode: false,
-            can_frequency: 1000000,
+            listen_only: false,
+            local_priority: false,
+            tx_error_backoff: None,
+            can_frequency: Bitrate::Mbps1.into(),
             clock_source: ClockSource::Soscdiv2,
         }
     }
 }
 
+impl CanSettings {
+    /// Start from the default settings, see `CanSettingsBuilder`
+    pub fn builder() -> CanSettingsBuilder {
+        CanSettingsBuilder {
+            settings: CanSettings::default(),
+        }
+    }
+}
+
+/// Builds `CanSettings`, checking that the fields make sense together
+///
+/// ```rust
+/// let settings = CanSettings::builder()
+///     .bitrate(can::Bitrate::Kbps500)
+///     .self_reception(false)
+///     .build()?;
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CanSettingsBuilder {
+    settings: CanSettings,
+}
+
+impl CanSettingsBuilder {
+    pub fn warning_interrupt(mut self, warning_interrupt: bool) -> Self {
+        self.settings.warning_interrupt = warning_interrupt;
+        self
+    }
+
+    pub fn self_reception(mut self, self_reception: bool) -> Self {
+        self.settings.self_reception = self_reception;
+        self
+    }
+
+    pub fn individual_masking(mut self, individual_masking: bool) -> Self {
+        self.settings.individual_masking = individual_masking;
+        self
+    }
+
+    pub fn loopback(mut self, loopback_mode: bool) -> Self {
+        self.settings.loopback_mode = loopback_mode;
+        self
+    }
+
+    pub fn listen_only(mut self, listen_only: bool) -> Self {
+        self.settings.listen_only = listen_only;
+        self
+    }
+
+    pub fn clock_source(mut self, clock_source: ClockSource) -> Self {
+        self.settings.clock_source = clock_source;
+        self
+    }
+
+    pub fn tx_error_backoff(mut self, tx_error_backoff: Option<u8>) -> Self {
+        self.settings.tx_error_backoff = tx_error_backoff;
+        self
+    }
+
+    pub fn local_priority(mut self, local_priority: bool) -> Self {
+        self.settings.local_priority = local_priority;
+        self
+    }
+
+    /// The bitrate in bit/s, either a `Bitrate` preset or any `u32`
+    pub fn bitrate<B: Into<u32>>(mut self, bitrate: B) -> Self {
+        self.settings.can_frequency = bitrate.into();
+        self
+    }
+
+    /// Return the settings, or `SettingsError` if they contradict each other
+    ///
+    /// Rejected are a bitrate of 0 or above 1 Mbit/s, loopback without self reception, where the
+    /// controller could only ever receive frames it discards, and loopback in listen only mode,
+    /// where it has nothing to loop back. Whether the bitrate can be made
+    /// from the source clock is only known at `init`.
+    pub fn build(self) -> Result<CanSettings, CanError> {
+        let settings = self.settings;
+        if settings.can_frequency == 0 || settings.can_frequency > u32::from(Bitrate::Mbps1) {
+            Err(CanError::SettingsError)
+        } else if settings.loopback_mode && !settings.self_reception {
+            Err(CanError::SettingsError)
+        } else if settings.loopback_mode && settings.listen_only {
+            Err(CanError::SettingsError)
+        } else {
+            Ok(settings)
+        }
+    }
+}
+
 /// This bit selects the clock source to the CAN Protocol Engine (PE) to be either the peripheral clock or the
 /// oscillator clock.
+///
+/// The bit timing is computed from the frequency of the selected clock as reported by `Spc`, no
+/// PCC clock source selection is involved.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ClockSource {
     /// The CAN engine clock source is the oscillator clock. Under this condition, the oscillator clock
     /// frequency must be lower than the bus clock.
     Soscdiv2,
 
-    /// The CAN engine clock source is the peripheral clock.
+    /// The CAN engine clock source is the peripheral clock, `SYS_CLK`, which runs at the
+    /// `CORE_CLK` frequency.
     Sys,
 }
 
@@ -281,21 +1806,21 @@ impl Default for ClockSource {
     }
 }
 
-#[derive(Clone, PartialEq)]
-enum MessageBufferCode {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageBufferCode {
     Receive(ReceiveBufferCode),
     Transmit(TransmitBufferState),
 }
 
-#[derive(Clone, PartialEq)]
-struct ReceiveBufferCode {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReceiveBufferCode {
     pub state: ReceiveBufferState,
     /// FlexCAN is updating the contents of the MB, the CPU must not access the MB
     pub busy: bool,
 }
 
-#[derive(Clone, PartialEq)]
-enum ReceiveBufferState {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiveBufferState {
     /// MB is not active
     Inactive,
 
@@ -312,8 +1837,8 @@ enum ReceiveBufferState {
     Ranswer,
 }
 
-#[derive(Clone, PartialEq)]
-enum TransmitBufferState {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransmitBufferState {
     /// MB is not active
     Inactive,
 
@@ -409,7 +1934,9 @@ impl MessageBufferCode {
     }
 }
 
-struct MailboxHeader {
+/// The control and status fields of a mailbox
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MailboxHeader {
     /// This bit indicates if the transmitting node is error active or error passive.
     pub error_state_indicator: bool,
 
@@ -425,6 +1952,13 @@ struct MailboxHeader {
     /// sense for Tx mailboxes. These bits are not transmitted. They are appended to the regular
     /// ID to define the transmission priority.
     pub priority: u8,
+
+    /// The DLC field, if it differs from what the frame's data length gives
+    ///
+    /// When transmitting this overrides the DLC derived from the frame, see
+    /// `Can::transmit_with_dlc`. When receiving it's the raw DLC of the frame, which can be 9 to 15
+    /// for classic frames.
+    pub dlc: Option<u8>,
 }
 
 impl MailboxHeader {
@@ -434,6 +1968,7 @@ impl MailboxHeader {
             code: MessageBufferCode::Transmit(TransmitBufferState::Inactive),
             time_stamp: 0,
             priority: 0,
+            dlc: None,
         }
     }
 
@@ -446,8 +1981,253 @@ impl MailboxHeader {
             }),
             time_stamp: 0,
             priority: 0,
+            dlc: None,
+        }
+    }
+}
+
+/// The header arming a mailbox to answer remote frames
+fn auto_response_header() -> MailboxHeader {
+    let mut header = MailboxHeader::default_receive();
+    header.code = MessageBufferCode::Receive(ReceiveBufferCode {
+        state: ReceiveBufferState::Ranswer,
+        busy: false,
+    });
+    header
+}
+
+/// Reset the controller and apply `settings`, leaving it out of freeze mode
+///
+/// With `rx_fifo` set, the RX FIFO is enabled in DMA mode instead of the receive mailboxes.
+fn configure(
+    can: &can0::RegisterBlock,
+    source_frequency: u32,
+    settings: &CanSettings,
+    rx_fifo: bool,
+) -> Result<(), CanError> {
+    // TODO: check if message_buffer_settings are longer than max MB available
+
+    // Touching the registers with the gate disabled would be a bus fault
+    if !pcc::can_enabled(can) {
+        return Err(CanError::ClockGateDisabled);
+    }
+
+    let timing = BitTiming::new(source_frequency, settings.can_frequency)?;
+
+    reset(can);
+
+    // first set clock source
+    can.ctrl1
+        .modify(|_, w| w.clksrc().bit(settings.clock_source == ClockSource::Sys));
+
+    enable(can);
+    enter_freeze(can);
+
+    can.mcr.modify(|_, w| {
+        w.rfen()
+            .bit(rx_fifo)
+            .srxdis()
+            .bit(!settings.self_reception)
+            .irmq()
+            .bit(settings.individual_masking)
+            .aen()
+            .bit(true)
+            .lprioen()
+            .bit(settings.local_priority)
+            .wrnen()
+            .bit(settings.warning_interrupt)
+            .dma()
+            .bit(rx_fifo);
+        unsafe { w.maxmb().bits((RX_MAILBOXES + TX_MAILBOXES) as u8 - 1) };
+        w
+    });
+
+    timing.write(can);
+    can.ctrl1.modify(|_, w| {
+        w.lpb()
+            .bit(settings.loopback_mode)
+            .lom()
+            .bit(settings.listen_only)
+            .twrnmsk()
+            .bit(settings.warning_interrupt)
+            .rwrnmsk()
+            .bit(settings.warning_interrupt)
+    });
+
+    // set filter mask to accept all
+    // TODO: Make better logic for setting filters
+    can.rxmgmask.write(unsafe { |w| w.bits(0) });
+    // The individual masks and the mailbox 14/15 masks are RAM without a defined reset value
+    for mask in can.rximr.iter() {
+        mask.write(|w| unsafe { w.bits(0) });
+    }
+    can.rx14mask.write(unsafe { |w| w.bits(0) });
+    can.rx15mask.write(unsafe { |w| w.bits(0) });
+
+    /*
+    • Initialize the Message Buffers
+    • The Control and Status word of all Message Buffers must be initialized
+    • If Rx FIFO was enabled, the ID filter table must be initialized
+    • Other entries in each Message Buffer should be initialized as required
+     */
+
+    let filter_frame = CanFrame::from(ExtendedDataFrame::new(ExtendedID::new(0))); // TODO: set filters better then on extended data frames
+
+    let first_tx_mailbox = if rx_fifo { RX_FIFO_MAILBOXES } else { 0 };
+
+    for mb in first_tx_mailbox..(first_tx_mailbox + TX_MAILBOXES) {
+        inactivate_mailbox(can, mb as usize);
+        write_mailbox(
+            can,
+            &MailboxHeader::default_transmit(),
+            &filter_frame,
+            mb as usize,
+        )
+        .unwrap();
+    }
+
+    if rx_fifo {
+        // accept everything into the FIFO
+        can.rxfgmask.write(unsafe { |w| w.bits(0) });
+        for word in RX_FIFO_FILTER_TABLE..(RX_FIFO_FILTER_TABLE + RX_FIFO_FILTERS) {
+            can.embedded_ram[word].write(|w| unsafe { w.bits(0) });
+        }
+    } else {
+        for mb in TX_MAILBOXES..(TX_MAILBOXES + RX_MAILBOXES) {
+            inactivate_mailbox(can, mb as usize);
+            write_mailbox(
+                can,
+                &MailboxHeader::default_receive(),
+                &filter_frame,
+                mb as usize,
+            )
+            .unwrap();
+        }
+    }
+
+    // clear all interrupt flags so data wont dangle
+    can.iflag1.write(|w| unsafe { w.bits(0xffff_ffff) });
+
+    leave_freeze(can);
+
+    // Read back the configuration, so a controller that didn't accept it is caught here rather than
+    // showing up as a silent bus later
+    let mcr = can.mcr.read();
+    let mcr_applied = mcr.mdis().is_0()
+        && mcr.frzack().is_0()
+        && mcr.rfen().bit() == rx_fifo
+        && mcr.srxdis().bit() == !settings.self_reception
+        && mcr.irmq().bit() == settings.individual_masking
+        && mcr.aen().bit()
+        && mcr.lprioen().bit() == settings.local_priority
+        && mcr.wrnen().bit() == settings.warning_interrupt
+        && mcr.dma().bit() == rx_fifo
+        && u32::from(mcr.maxmb().bits()) == (RX_MAILBOXES + TX_MAILBOXES) as u32 - 1;
+
+    let ctrl1 = can.ctrl1.read();
+    let ctrl1_applied = ctrl1.clksrc().bit() == (settings.clock_source == ClockSource::Sys)
+        && timing.is_applied(can)
+        && ctrl1.lpb().bit() == settings.loopback_mode
+        && ctrl1.lom().bit() == settings.listen_only;
+
+    if mcr_applied && ctrl1_applied {
+        Ok(())
+    } else {
+        Err(CanError::ConfigurationFailed)
+    }
+}
+
+/// The timing fields of `CTRL1`
+struct BitTiming {
+    presdiv: u32,
+    propseg: u32,
+    pseg1: u32,
+    pseg2: u32,
+    rjw: u32,
+}
+
+/// The frequency of the clock selected by `settings.clock_source`
+fn source_frequency(spc: &spc::Spc, settings: &CanSettings) -> Result<u32, CanError> {
+    match settings.clock_source {
+        ClockSource::Sys => spc.core_freq().map_err(|_| CanError::ClockSourceDisabled),
+        ClockSource::Soscdiv2 => spc.soscdiv2_freq().ok_or(CanError::ClockSourceDisabled),
+    }
+}
+
+impl BitTiming {
+    /// Compute the timing for `can_frequency` from a source clock of `source_frequency`
+    fn new(source_frequency: u32, can_frequency: u32) -> Result<Self, CanError> {
+        let (presdiv, tqs) = bit_timing(source_frequency, can_frequency)?;
+
+        // Table 50-26 in datasheet, can standard compliant settings
+        let (pseg2, rjw) = if tqs >= 8 && tqs < 10 {
+            (1, 1)
+        } else if tqs >= 10 && tqs < 15 {
+            (3, 2)
+        } else if tqs >= 15 && tqs < 20 {
+            (6, 2)
+        } else if tqs >= 20 && tqs < 26 {
+            (7, 3)
+        } else {
+            unreachable!("there should be between 8 and 25 tqs in an bit");
+        };
+
+        let pseg1 = ((tqs - (pseg2 + 1)) / 2) - 1;
+        let propseg = tqs - (pseg2 + 1) - (pseg1 + 1) - 2;
+
+        Ok(BitTiming {
+            presdiv: presdiv,
+            propseg: propseg,
+            pseg1: pseg1,
+            pseg2: pseg2,
+            rjw: rjw,
+        })
+    }
+
+    /// Write the timing fields, the controller must be in freeze mode
+    fn write(&self, can: &can0::RegisterBlock) {
+        can.ctrl1.modify(|_, w| unsafe {
+            w.presdiv()
+                .bits(self.presdiv as u8)
+                .pseg1()
+                .bits(self.pseg1 as u8)
+                .pseg2()
+                .bits(self.pseg2 as u8)
+                .propseg()
+                .bits(self.propseg as u8)
+                .rjw()
+                .bits(self.rjw as u8)
+        });
+    }
+
+    fn is_applied(&self, can: &can0::RegisterBlock) -> bool {
+        let ctrl1 = can.ctrl1.read();
+        u32::from(ctrl1.presdiv().bits()) == self.presdiv
+            && u32::from(ctrl1.pseg1().bits()) == self.pseg1
+            && u32::from(ctrl1.pseg2().bits()) == self.pseg2
+            && u32::from(ctrl1.propseg().bits()) == self.propseg
+            && u32::from(ctrl1.rjw().bits()) == self.rjw
+    }
+}
+
+/// Find the prescaler and the number of time quanta in a bit that gives exactly `can_frequency`
+///
+/// The highest number of time quanta is preferred since it gives the finest control over the
+/// sample point.
+fn bit_timing(source_frequency: u32, can_frequency: u32) -> Result<(u32, u32), CanError> {
+    if can_frequency == 0 || source_frequency % can_frequency != 0 {
+        return Err(CanError::SettingsError);
+    }
+
+    let ratio = source_frequency / can_frequency;
+    for tqs in (8..26).rev() {
+        // PRESDIV is 8 bits wide
+        if ratio % tqs == 0 && ratio / tqs <= 256 {
+            return Ok((ratio / tqs - 1, tqs));
         }
     }
+
+    Err(CanError::SettingsError)
 }
 
 fn enable(can: &can0::RegisterBlock) {
@@ -455,6 +2235,11 @@ fn enable(can: &can0::RegisterBlock) {
     while can.mcr.read().lpmack().is_1() {}
 }
 
+fn disable(can: &can0::RegisterBlock) {
+    can.mcr.modify(|_, w| w.mdis()._1());
+    while can.mcr.read().lpmack().is_0() {}
+}
+
 fn reset(can: &can0::RegisterBlock) {
     can.mcr.modify(|_, w| w.mdis()._1());
     while can.mcr.read().lpmack().is_0() {}
@@ -477,17 +2262,100 @@ fn leave_freeze(can: &can0::RegisterBlock) {
     while can.mcr.read().frzack().is_1() {}
 }
 
+/// Raw register values read by `Can::debug_snapshot`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CanDebug {
+    pub mcr: u32,
+    pub ctrl1: u32,
+
+    /// Error and status, including the fault confinement state and the last bus errors
+    pub esr1: u32,
+
+    /// The transmit and receive error counters
+    pub ecr: u32,
+    pub iflag1: u32,
+    pub imask1: u32,
+}
+
 #[derive(Debug)]
 pub enum CanError {
     FreezeModeError,
     ClockSourceDisabled,
     SettingsError,
+
+    /// The controller's clock gate is disabled, see `pcc::Pcc::enable_can0`
+    ClockGateDisabled,
+
     ConfigurationFailed,
     BusyMailboxWriteAttempted,
+
+    /// The software transmit queue is full, see `Can::enqueue`
+    QueueFull,
+
+    /// A standard ID above `MAX_BASE_ID` or an extended ID above `MAX_EXTENDED_ID`
+    IdOutOfRange,
+}
+
+/// The number of data bytes a DLC stands for, `fd` for CAN FD frames (the `EDL` bit)
+///
+/// Classic frames carry at most 8 bytes, DLC 9 to 15 also mean 8. CAN FD frames use DLC 9 to 15
+/// for 12, 16, 20, 24, 32, 48 and 64 bytes. The controller is run without CAN FD, but a received
+/// frame's length is still clamped to the mailbox size, so a malformed DLC can't make the driver
+/// read the next mailbox.
+pub fn data_length(dlc: u8, fd: bool) -> usize {
+    match (dlc & 0xf, fd) {
+        (dlc, _) if dlc <= 8 => dlc as usize,
+        (_, false) => 8,
+        (9, true) => 12,
+        (10, true) => 16,
+        (11, true) => 20,
+        (12, true) => 24,
+        (13, true) => 32,
+        (14, true) => 48,
+        (_, true) => 64,
+    }
+}
+
+/// An ID mask for `Can::set_individual_mask`, set bits must match
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IdMask {
+    /// Mask for a standard ID, at most `MAX_BASE_ID`
+    Base(u16),
+
+    /// Mask for an extended ID, at most `MAX_EXTENDED_ID`
+    Extended(u32),
+}
+
+impl IdMask {
+    /// The mask in the layout of the mailbox ID word, `None` if it has bits beyond the ID
+    fn bits(&self) -> Option<u32> {
+        match *self {
+            IdMask::Base(mask) if mask <= MAX_BASE_ID => Some(u32::from(mask) << 18),
+            IdMask::Extended(mask) if mask <= MAX_EXTENDED_ID => Some(mask),
+            _ => None,
+        }
+    }
+}
+
+/// The index in `embedded_ram` of the control and status word of `mailbox`
+///
+/// Mailboxes keep their numbers when the RX FIFO is enabled, the FIFO just occupies the first
+/// `RX_FIFO_MAILBOXES` of them, so this only depends on the payload size. All mailbox accesses go
+/// through here, so supporting larger (CAN FD) payloads only means changing the stride.
+///
+/// Panics if `mailbox` lies beyond the message buffer RAM.
+fn mailbox_address(can: &can0::RegisterBlock, mailbox: usize) -> usize {
+    let address = mailbox * MAILBOX_WORDS;
+    assert!(
+        address + MAILBOX_WORDS <= can.embedded_ram.len(),
+        "mailbox {} out of range",
+        mailbox
+    );
+    address
 }
 
 fn read_mailbox_code(can: &can0::RegisterBlock, mailbox: usize) -> MessageBufferCode {
-    let start_adress = mailbox * 4;
+    let start_adress = mailbox_address(can, mailbox);
     let code = MessageBufferCode::decode(
         can.embedded_ram[start_adress]
             .read()
@@ -500,9 +2368,86 @@ fn read_mailbox_code(can: &can0::RegisterBlock, mailbox: usize) -> MessageBuffer
     code
 }
 
+fn read_mailbox_time_stamp(can: &can0::RegisterBlock, mailbox: usize) -> u16 {
+    let time_stamp = can.embedded_ram[mailbox_address(can, mailbox)]
+        .read()
+        .bits()
+        .get_bits(0..16) as u16;
+    // Reading the control and status word locks the mailbox, reading the timer unlocks it again.
+    let _time = can.timer.read();
+    time_stamp
+}
+
+/// Read the ID of a receive mailbox, leaving its interrupt flag set and the mailbox unlocked
+fn read_mailbox_id(can: &can0::RegisterBlock, mailbox: usize) -> ID {
+    let start_adress = mailbox_address(can, mailbox);
+
+    // Reading the control and status word locks the mailbox, wait for a move in to finish
+    let mut cs = can.embedded_ram[start_adress].read().bits();
+    while let Ok(MessageBufferCode::Receive(ReceiveBufferCode { busy: true, .. })) =
+        MessageBufferCode::decode(cs.get_bits(24..28) as u8)
+    {
+        cs = can.embedded_ram[start_adress].read().bits();
+    }
+
+    let id_word = can.embedded_ram[start_adress + 1].read().bits();
+    let id = if cs.get_bit(21) {
+        ID::ExtendedID(ExtendedID::new(id_word.get_bits(0..29)))
+    } else {
+        ID::BaseID(BaseID::new(id_word.get_bits(18..29) as u16))
+    };
+
+    // Reading the timer unlocks the mailbox again
+    let _time = can.timer.read();
+    id
+}
+
+/// Return a mailbox used by `Can::request` to the transmit path
+///
+/// The remote frame is aborted if it hasn't been sent yet, otherwise the mailbox is a receive
+/// mailbox waiting for the answer. Either way it ends up as an inactive transmit mailbox with its
+/// interrupt flag cleared.
+fn release_request_mailbox(can: &can0::RegisterBlock, mailbox: usize) {
+    if read_mailbox_code(can, mailbox)
+        == MessageBufferCode::Transmit(TransmitBufferState::DataRemote)
+    {
+        abort_mailbox(can, mailbox);
+    }
+
+    can.embedded_ram[mailbox_address(can, mailbox)].write(|w| unsafe {
+        w.bits(
+            0u32.set_bits(
+                24..28,
+                u8::from(MessageBufferCode::Transmit(TransmitBufferState::Inactive)) as u32,
+            )
+            .get_bits(0..32),
+        )
+    });
+    can.iflag1.write(|w| unsafe { w.bits(1 << mailbox) });
+}
+
+/// Check if a transmit mailbox can be written, acknowledging a completed transmission
+///
+/// The interrupt flag of a transmit mailbox is set when its frame has been sent. A mailbox with the
+/// flag set is inactivated and reused, even if its code hasn't been seen as inactive yet.
+fn reclaim_tx_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> bool {
+    if read_mailbox_code(can, mailbox) == MessageBufferCode::Transmit(TransmitBufferState::Inactive)
+    {
+        return true;
+    }
+
+    if can.iflag1.read().bits().get_bit(mailbox) {
+        can.iflag1.write(|w| unsafe { w.bits(1 << mailbox) });
+        inactivate_mailbox(can, mailbox);
+        true
+    } else {
+        false
+    }
+}
+
 fn abort_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> Option<CanFrame> {
     // TODO: this function is untested, test it (it requires mcr.aen() bit set as well)
-    let start_adress = mailbox * 4;
+    let start_adress = mailbox_address(can, mailbox);
     if MessageBufferCode::decode(
         can.embedded_ram[start_adress]
             .read()
@@ -541,7 +2486,7 @@ fn abort_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> Option<CanFrame>
 ///  - A frame containing the message within the inactivated Tx Mailbox may be transmitted without setting the respective IFLAG
 fn inactivate_mailbox(can: &can0::RegisterBlock, mailbox: usize) {
     //TODO: consider clearing interrupt
-    let start_adress = mailbox * 4;
+    let start_adress = mailbox_address(can, mailbox);
     match MessageBufferCode::decode(
         can.embedded_ram[start_adress]
             .read()
@@ -585,7 +2530,7 @@ fn write_mailbox(
     frame: &CanFrame,
     mailbox: usize,
 ) -> Result<(), CanError> {
-    let start_adress = mailbox * 4;
+    let start_adress = mailbox_address(can, mailbox);
 
     // Check if the mailbox is ready for a write
     let current_code = can.embedded_ram[start_adress]
@@ -650,7 +2595,9 @@ fn write_mailbox(
         };
     }
 
-    // 4. Write the data bytes.
+    // 4. Write the data bytes, bytes beyond the frame's data are left as 0
+    can.embedded_ram[start_adress + 2].write(|w| unsafe { w.bits(0) });
+    can.embedded_ram[start_adress + 3].write(|w| unsafe { w.bits(0) });
     let data_length = if let CanFrame::DataFrame(data_frame) = *frame {
         for index in 0..data_frame.data().len() as usize {
             can.embedded_ram[start_adress + 2 + index / 4].modify(|r, w| {
@@ -681,8 +2628,8 @@ fn write_mailbox(
                 .set_bit(22, true) // SRR needs to be 1 to adhere to can specs
                 .set_bit(21, extended_id)
                 .set_bit(20, remote_frame)
-                .set_bits(16..20, data_length as u32)
-                .set_bits(0..15, header.time_stamp as u32)
+                .set_bits(16..20, header.dlc.map_or(data_length as u32, u32::from))
+                .set_bits(0..16, header.time_stamp as u32)
                 .get_bits(0..32),
         )
     });
@@ -690,10 +2637,22 @@ fn write_mailbox(
     Ok(())
 }
 
+/// Read a receive mailbox and acknowledge its interrupt flag
+///
+/// The order of the accesses is what makes the frame consistent while frames keep arriving:
+///
+/// 1. Reading the control and status word locks the mailbox. No new frame is moved into it until
+///    it is unlocked, a frame matching it waits in the serial message buffer meanwhile.
+/// 2. While `CODE` has the busy bit set the controller is still moving a frame in, so the word is
+///    read again until the move has finished. The lock takes effect once it's done.
+/// 3. The ID and data words are read, they belong to the same frame as the final control word.
+/// 4. The interrupt flag is acknowledged while the mailbox is still locked. A frame moved in after
+///    the unlock raises the flag again, acknowledging after the unlock could clear its flag.
+/// 5. Reading the free running timer unlocks the mailbox.
+///
+/// A mailbox whose `CODE` reads `OVERRUN` holds the newest frame, the older ones were lost.
 fn read_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> (MailboxHeader, CanFrame) {
-    let start_adress = mailbox * 4;
-
-    // TODO: Check that mailbox is within valid range and return error (panic?) if not
+    let start_adress = mailbox_address(can, mailbox);
 
     // 1. Read control and Status word
     let mut cs = can.embedded_ram[start_adress].read().bits();
@@ -716,17 +2675,18 @@ fn read_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> (MailboxHeader, Ca
             can.embedded_ram[start_adress + 1]
                 .read()
                 .bits()
-                .get_bits(0..28),
+                .get_bits(0..29),
         ))
     } else {
         ID::BaseID(BaseID::new(
             can.embedded_ram[start_adress + 1]
                 .read()
                 .bits()
-                .get_bits(18..28) as u16,
+                .get_bits(18..29) as u16,
         ))
     };
-    let dlc = cs.get_bits(16..20) as usize;
+    // Never read past the data words of this mailbox, whatever the DLC says
+    let dlc = data_length(cs.get_bits(16..20) as u8, cs.get_bit(31)).min(MAILBOX_DATA_BYTES);
 
     let remote_frame = cs.get_bit(20);
 
@@ -753,14 +2713,15 @@ fn read_mailbox(can: &can0::RegisterBlock, mailbox: usize) -> (MailboxHeader, Ca
     let header = MailboxHeader {
         error_state_indicator: cs.get_bit(29),
         code: MessageBufferCode::decode(cs.get_bits(24..28) as u8).unwrap(),
-        time_stamp: cs.get_bits(0..15) as u16,
+        time_stamp: cs.get_bits(0..16) as u16,
         priority: priority as u8,
+        dlc: Some(cs.get_bits(16..20) as u8),
     };
 
-    // 4. Ack proper flag
+    // 4. Ack proper flag, before unlocking
     can.iflag1.write(|w| unsafe { w.bits(1 << mailbox) });
 
-    // 6. Read Free running timer to unlock mailbox
+    // 5. Read Free running timer to unlock mailbox
     let _time = can.timer.read();
 
     (header, frame.into())
@@ -2,27 +2,118 @@
 //!
 //! The most common consoles in use for this chip are:
 //!  - LPUART (LPUART1 is the one connected to the OpenSDA chip on s32k144evb)
-//!  - ITM
-// TODO: implement and test ITM
+//!  - ITM (with the `itm` feature), see `console::itm`
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
 
 use crate::lpuart;
 use crate::spc;
 use embedded_types;
+use embedded_types::io::Read;
 use s32k144;
 
+/// Capacity of the transmit buffer used with `WriteMode::Interrupt`
+pub const TX_BUFFER_SIZE: usize = 256;
+
 impl<'p> embedded_types::io::Write for LpuartConsole<'p> {
     fn write(&mut self, buf: &[u8]) -> embedded_types::io::Result<usize> {
+        if self.write_mode == WriteMode::Interrupt {
+            let written = interrupt::free(|cs| self.tx_buffer.borrow(cs).borrow_mut().push(buf));
+            self.lpuart.set_transmit_interrupt(true);
+            return Ok(written);
+        }
+
         for i in 0..buf.len() {
-            match self.lpuart.transmit(buf[i]) {
-                Ok(()) => (),
-                Err(embedded_types::io::Error::BufferExhausted) => return Ok(i),
-                Err(e) => return Err(e),
+            loop {
+                match self.lpuart.transmit(buf[i]) {
+                    Ok(()) => break,
+                    Err(embedded_types::io::Error::BufferExhausted) => {
+                        if self.write_mode == WriteMode::Buffered {
+                            return Ok(i);
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
             }
         }
+
+        if self.write_mode == WriteMode::Synchronous {
+            self.flush();
+        }
         Ok(buf.len())
     }
 }
 
+/// How `LpuartConsole` writes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Fill the transmit FIFO and return, writing only what fits
+    Buffered,
+
+    /// Wait for room for every byte, and return once the last one has left the wire
+    ///
+    /// Nothing is lost if the chip halts or resets right after, as on panic.
+    Synchronous,
+
+    /// Copy into a buffer of `TX_BUFFER_SIZE` bytes and return, writing only what fits
+    ///
+    /// The buffer is emptied into the FIFO by `LpuartConsole::on_interrupt`, which must be called
+    /// from the LPUART's interrupt handler with the interrupt enabled in the NVIC.
+    Interrupt,
+}
+
+/// Bytes waiting to be moved to the transmit FIFO
+struct TxBuffer {
+    data: [u8; TX_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl TxBuffer {
+    fn new() -> Self {
+        TxBuffer {
+            data: [0; TX_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Append as much of `buf` as fits, returning how many bytes were taken
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let count = buf.len().min(TX_BUFFER_SIZE - self.len);
+        for &byte in &buf[..count] {
+            self.data[(self.start + self.len) % TX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+        count
+    }
+
+    fn front(&self) -> Option<u8> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.data[self.start])
+        }
+    }
+
+    fn pop_front(&mut self) {
+        self.start = (self.start + 1) % TX_BUFFER_SIZE;
+        self.len -= 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for WriteMode {
+    fn default() -> Self {
+        WriteMode::Buffered
+    }
+}
+
 impl<'p> embedded_types::io::Read for LpuartConsole<'p> {
     fn read_until(&mut self, byte: u8, buf: &mut [u8]) -> embedded_types::io::Result<usize> {
         let mut index = 0;
@@ -46,15 +137,218 @@ impl<'p> embedded_types::io::Read for LpuartConsole<'p> {
 /// Allow usage of uart as a Console
 pub struct LpuartConsole<'a> {
     lpuart: lpuart::Lpuart<'a>,
+    write_mode: WriteMode,
+    tx_buffer: Mutex<RefCell<TxBuffer>>,
 }
 
 impl<'a> LpuartConsole<'a> {
-    pub fn init(lpuart: &'a s32k144::lpuart0::RegisterBlock, spc: &'a spc::Spc<'a>) -> Self {
+    /// Initialize the LPUART at 115200 baud from an 8 MHz functional clock
+    ///
+    /// Fails with `UartError::ClockGateDisabled` unless the LPUART's clock gate has been enabled,
+    /// e.g. with `pcc::Pcc::enable_lpuart1`.
+    pub fn init(
+        lpuart: &'a s32k144::lpuart0::RegisterBlock,
+        spc: &'a spc::Spc<'a>,
+    ) -> Result<Self, lpuart::UartError> {
         let mut uart_config = lpuart::Config::default();
         uart_config.baudrate = 115200;
 
-        LpuartConsole {
-            lpuart: lpuart::Lpuart::init(lpuart, spc, uart_config, 8_000_000).unwrap(),
+        Ok(LpuartConsole {
+            lpuart: lpuart::Lpuart::init(lpuart, spc, uart_config, 8_000_000)?,
+            write_mode: WriteMode::default(),
+            tx_buffer: Mutex::new(RefCell::new(TxBuffer::new())),
+        })
+    }
+
+    /// Select how writes are done, `WriteMode::Buffered` by default
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) {
+        self.write_mode = write_mode;
+    }
+
+    /// Wait until everything written has left the wire
+    ///
+    /// With `WriteMode::Interrupt` this relies on `on_interrupt` emptying the buffer, so it must
+    /// not be called with the LPUART interrupt masked.
+    pub fn flush(&self) {
+        while !interrupt::free(|cs| self.tx_buffer.borrow(cs).borrow().is_empty()) {}
+        while !self.lpuart.tx_complete() {}
+    }
+
+    /// Move buffered bytes to the transmit FIFO, call this from the LPUART interrupt handler
+    ///
+    /// Disables the transmit interrupt once the buffer is empty, `write` enables it again.
+    pub fn on_interrupt(&self) {
+        interrupt::free(|cs| {
+            let mut tx_buffer = self.tx_buffer.borrow(cs).borrow_mut();
+            while let Some(byte) = tx_buffer.front() {
+                if self.lpuart.transmit(byte).is_err() {
+                    return;
+                }
+                tx_buffer.pop_front();
+            }
+            self.lpuart.set_transmit_interrupt(false);
+        });
+    }
+
+    /// Like `init`, but first mux the LPUART's TX and RX signals to `tx` and `rx`
+    ///
+    /// For boards or LPUART instances not wired like LPUART1 on s32k144evb.
+    pub fn init_with_pins(
+        lpuart: &'a s32k144::lpuart0::RegisterBlock,
+        spc: &'a spc::Spc<'a>,
+        tx: &lpuart::Pin,
+        rx: &lpuart::Pin,
+    ) -> Result<Self, lpuart::UartError> {
+        lpuart::configure_pins(tx, rx);
+        Self::init(lpuart, spc)
+    }
+
+    /// Discard everything in the UART FIFOs, e.g. to resynchronize after a burst of framing errors
+    pub fn flush_fifos(&self) {
+        self.lpuart.flush_fifos();
+    }
+
+    /// Read a line (including the `\n`) into `buf` and return it as a `str`
+    ///
+    /// Bytes that are not valid UTF-8, including a multi-byte sequence cut off at the end of
+    /// `buf`, are dropped instead of causing an error.
+    pub fn read_line_lossy<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+    ) -> embedded_types::io::Result<&'b str> {
+        let len = self.read_until(b'\n', buf)?;
+
+        // Move the valid parts to the front of `buf`, skipping the invalid ones
+        let mut valid = 0;
+        let mut index = 0;
+        while index < len {
+            match core::str::from_utf8(&buf[index..len]) {
+                Ok(_) => {
+                    buf.copy_within(index..len, valid);
+                    valid += len - index;
+                    break;
+                }
+                Err(e) => {
+                    let good = e.valid_up_to();
+                    buf.copy_within(index..index + good, valid);
+                    valid += good;
+                    index += good + e.error_len().unwrap_or(len - index - good);
+                }
+            }
+        }
+
+        Ok(core::str::from_utf8(&buf[..valid]).unwrap_or_default())
+    }
+}
+
+/// The valid error types for `LineReader::read_line`
+#[derive(Debug)]
+pub enum LineError {
+    /// The line didn't fit in the reader's buffer, it has been discarded up to its `\n`
+    LineTooLong,
+
+    /// The line isn't valid UTF-8
+    InvalidUtf8,
+
+    /// Receiving failed, e.g. with a framing or parity error
+    Io(embedded_types::io::Error),
+}
+
+/// A line reader with a buffer of `N` bytes, for REPL style command input
+///
+/// ```rust
+/// let mut reader = console::LineReader::<64>::new();
+/// let line = reader.read_line(&mut console)?;
+/// ```
+pub struct LineReader<const N: usize> {
+    buf: [u8; N],
+}
+
+impl<const N: usize> LineReader<N> {
+    pub fn new() -> Self {
+        LineReader { buf: [0; N] }
+    }
+
+    /// Block until a line has been received, and return it without its `\n` or `\r\n`
+    ///
+    /// A line longer than `N` bytes isn't truncated, the rest of it is received and thrown away
+    /// before returning `LineError::LineTooLong`, so the next call starts at a line boundary.
+    pub fn read_line(&mut self, console: &mut LpuartConsole) -> Result<&str, LineError> {
+        let mut len = 0;
+        let mut too_long = false;
+        loop {
+            let byte = match console.lpuart.receive() {
+                Ok(byte) => byte,
+                Err(embedded_types::io::Error::BufferExhausted) => continue,
+                Err(e) => return Err(LineError::Io(e)),
+            };
+
+            if byte == b'\n' {
+                break;
+            } else if len == N {
+                too_long = true;
+            } else {
+                self.buf[len] = byte;
+                len += 1;
+            }
+        }
+
+        if too_long {
+            return Err(LineError::LineTooLong);
+        }
+        if len > 0 && self.buf[len - 1] == b'\r' {
+            len -= 1;
+        }
+        core::str::from_utf8(&self.buf[..len]).map_err(|_| LineError::InvalidUtf8)
+    }
+}
+
+impl<const N: usize> Default for LineReader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writing to the ITM, output on the SWO pin
+///
+/// Unlike `panic-over-itm` this is meant for logging during normal operation.
+#[cfg(feature = "itm")]
+pub mod itm {
+    use cortex_m::itm::write_all;
+    use cortex_m::peripheral::itm::Stim;
+    use cortex_m::peripheral::ITM;
+    use embedded_types;
+
+    /// Allow usage of ITM stimulus port 0 as a Console
+    pub struct ItmWriter<'a> {
+        itm: &'a mut ITM,
+    }
+
+    impl<'a> ItmWriter<'a> {
+        pub fn new(itm: &'a mut ITM) -> Self {
+            ItmWriter { itm: itm }
+        }
+
+        /// Return true if the ITM and stimulus port 0 have been enabled by the debugger
+        pub fn is_enabled(&self) -> bool {
+            self.itm.tcr.read() & 1 != 0 && self.itm.ter[0].read() & 1 != 0
+        }
+
+        fn port(&mut self) -> &mut Stim {
+            &mut self.itm.stim[0]
+        }
+    }
+
+    impl<'a> embedded_types::io::Write for ItmWriter<'a> {
+        /// Write all of `buf` to stimulus port 0, blocking until the ITM FIFO has room
+        ///
+        /// When no debugger has enabled the port the data is discarded, so logging doesn't hang a
+        /// board running without a probe attached.
+        fn write(&mut self, buf: &[u8]) -> embedded_types::io::Result<usize> {
+            if self.is_enabled() {
+                write_all(self.port(), buf);
+            }
+            Ok(buf.len())
         }
     }
 }
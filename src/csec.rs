@@ -43,6 +43,7 @@
 //! let mut buffer: [u8; 16] = [0; 16];
 //!
 //! let csec = csec::CSEc::init(&p.FTFC, &p.CSE_PRAM);
+//! csec.init_rng().unwrap();
 //! let rnd_buf = csec.generate_rnd().unwrap();
 //! csec.load_plainkey(&PLAINKEY).unwrap();
 //! buffer.copy_from_slice(plaintext);
@@ -51,6 +52,24 @@
 //! assert!(plaintext == &buffer[..]);
 //! ```
 //!
+//! `encrypt_cbc_to`/`decrypt_cbc_to` read from one slice and write to another of equal length, so
+//! the copy into `buffer` above can be skipped:
+//!
+//! ```rust
+//! csec.encrypt_cbc_to(&rnd_buf, plaintext, &mut buffer).unwrap();
+//! ```
+//!
+//! For large buffers `encrypt_cbc_pointer`/`decrypt_cbc_pointer` pass the buffers to the engine
+//! by address instead of copying them through `CSE_PRAM`. Both buffers must be 32-bit aligned:
+//!
+//! ```rust
+//! #[repr(align(4))]
+//! struct Aligned([u8; 16]);
+//!
+//! let mut ciphertext = Aligned([0; 16]);
+//! csec.encrypt_cbc_pointer(&rnd_buf, &aligned_plaintext.0, &mut ciphertext.0).unwrap();
+//! ```
+//!
 //! The provided key is loaded onto the board's RAM key slot. Multiple key slots are available, but
 //! support for those are not yet implemented.
 //!
@@ -75,6 +94,24 @@
 //! assert!(csec.verify_mac(&plaintext, &cmac).unwrap());
 //! ```
 //!
+//! With the `rand_core` feature enabled, `CSEcRng` wraps the generator as a `rand_core::RngCore`:
+//! ```rust
+//! mod csec;
+//!
+//! let csec = csec::CSEc::init(&p.FTFC, &p.CSE_PRAM);
+//! let mut rng = csec::CSEcRng::new(&csec).unwrap();
+//! let x = rng.next_u32();
+//! ```
+//!
+//! - Self test
+//!
+//! `self_test` runs the RNG, key loading, CBC and MAC against known answers, e.g. at boot:
+//! ```rust
+//! let csec = csec::CSEc::init(&p.FTFC, &p.CSE_PRAM);
+//! csec.self_test().unwrap();
+//! csec.load_plainkey(&PLAINKEY).unwrap();
+//! ```
+//!
 //! ## Security
 //! During encryption the initialization vector must be random and unpredictable (for each
 //! message), and may be made public after encryption. It is then recommended to use the output of
@@ -83,6 +120,19 @@
 //! The initialization vector is required for decryption, so it is recommended to prefix it to the
 //! sent message. Only the key is a secret.
 //!
+//! `seal()` and `open()` implement this construction, authenticating the IV and the ciphertext
+//! with a MAC under a second key:
+//! ```rust
+//! let keys = csec::SealKeys {
+//!     encryption: ENCRYPTION_KEY,
+//!     mac: MAC_KEY,
+//! };
+//! let mut sealed = [0u8; 16 + 32];
+//! let len = csec.seal(&keys, plaintext, &mut sealed).unwrap();
+//! let mut opened = [0u8; 16];
+//! assert_eq!(csec.open(&keys, &sealed[..len], &mut opened).unwrap(), Some(16));
+//! ```
+//!
 //! ## Hardware API
 //! The API for the CSEc is 7 "pages" of 128-bit each in FTFC PRAM. Prefixed to these pages is a command header.
 //! To run a CSEc operation, data to be processed should first be written to these pages after
@@ -90,8 +140,12 @@
 //! header. See the images below.
 #![allow(dead_code)]
 
+use core::cell::Cell;
+use core::sync::atomic::{compiler_fence, Ordering};
 use s32k144;
 
+use crate::timeout;
+
 /// CSEc commands which follow the same values as the SHE command defenition.
 #[derive(Debug, Clone, Copy)]
 enum Command {
@@ -141,11 +195,24 @@ enum Command {
 /// Specifies how the data is transferred to/from the CSE.
 /// There are two use cases. One is to copy all data and the command function call method and the
 /// other is a pointer and function call method.
+#[derive(PartialEq)]
 enum Format {
     Copy = 0x0,
     Pointer,
 }
 
+/// The keys used by `CSEc::seal` and `CSEc::open`
+///
+/// The two keys must be independent, e.g. generated separately, never one derived from the other
+/// or the same key twice.
+pub struct SealKeys {
+    /// The AES-128 key the message is encrypted with
+    pub encryption: [u8; PAGE_SIZE_IN_BYTES],
+
+    /// The AES-128 key the CMAC is computed with
+    pub mac: [u8; PAGE_SIZE_IN_BYTES],
+}
+
 /// Specifies if the information is the first of a following function call.
 #[derive(PartialEq)]
 enum Sequence {
@@ -195,6 +262,9 @@ pub enum CommandResult {
     NoDebugging = 0x200,
     MemoryFailure = 0x400,
     GeneralError = 0x800,
+
+    /// Not a SHE error code: the command didn't complete within `COMMAND_TIMEOUT_CYCLES` cycles.
+    Timeout = 0x1000,
 }
 
 impl CommandResult {
@@ -227,9 +297,38 @@ fn u8_be_array_from_u32(x: u32) -> [u8; 4] {
     ]
 }
 
+/// The length of a MAC message of `len` bytes in bits, as given to the engine.
+///
+/// Fails if the length in bits doesn't fit in the 32-bit length field, the multiplication is
+/// checked so it can't wrap around on any `usize` width.
+fn message_length_in_bits(len: usize) -> Result<u32, CommandResult> {
+    len.checked_mul(8)
+        .filter(|&bits| bits <= u32::max_value() as usize)
+        .map(|bits| bits as u32)
+        .ok_or(CommandResult::GeneralError)
+}
+
+/// Compares two MACs without returning early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+
+    // Keep the compiler from turning the loop into an early exit
+    unsafe { core::ptr::read_volatile(&diff) == 0 }
+}
+
 pub struct CSEc {
     ftfc: s32k144::FTFC,
     cse_pram: s32k144::CSE_PRAM,
+
+    /// Set once `init_rng` has succeeded
+    rng_seeded: Cell<bool>,
 }
 
 const PAGE_1_OFFSET: usize = 16;
@@ -247,11 +346,50 @@ const MAC_MESSAGE_LENGTH_OFFSET: usize = 0xc;
 const MAC_VERIFICATION_BITS_OFFSET: usize = PAGE_1_OFFSET + 0x4;
 const MAC_LENGTH_OFFSET: usize = 0x8;
 
+// The pointer format of CMD_ENC_CBC/CMD_DEC_CBC, from the description of the "pointer method" in
+// NXP AN5401 (Using CSEc Security Module on S32K1xx): the number of pages stays in the low half
+// of word 3 (`PAGE_LENGTH_OFFSET`) like in the copy format, and where the copy format has the IV
+// and the data in pages 1 and up, the pointer format has three 32-bit addresses in words 4 to 6.
+const POINTER_INIT_VEC_OFFSET: usize = PAGE_1_OFFSET;
+const POINTER_INPUT_OFFSET: usize = PAGE_1_OFFSET + 0x4;
+const POINTER_OUTPUT_OFFSET: usize = PAGE_1_OFFSET + 0x8;
+
+/// Buffers passed to the engine by address must be aligned to a 32-bit boundary.
+pub const POINTER_ALIGNMENT: usize = 4;
+
+/// An initialization vector aligned for the pointer format
+#[repr(align(4))]
+struct AlignedPage([u8; PAGE_SIZE_IN_BYTES]);
+
+/// Known answer test vectors from NIST SP 800-38A (F.2.1) and RFC 4493 (example 2)
+const SELF_TEST_KEY: [u8; 16] = [
+    0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+];
+const SELF_TEST_INIT_VEC: [u8; 16] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+];
+const SELF_TEST_PLAINTEXT: [u8; 16] = [
+    0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
+];
+const SELF_TEST_CIPHERTEXT: [u8; 16] = [
+    0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9, 0x19, 0x7d,
+];
+const SELF_TEST_CMAC: [u8; 16] = [
+    0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a, 0x28, 0x7c,
+];
+
+/// How many core cycles a command may take before it's considered wedged.
+///
+/// This is 125 ms at an 80 MHz core clock. It only turns a hung engine into
+/// `CommandResult::Timeout`, it's not a bound on how long a command takes.
+pub const COMMAND_TIMEOUT_CYCLES: u32 = 10_000_000;
+
 impl CSEc {
     pub fn init(ftfc: s32k144::FTFC, cse_pram: s32k144::CSE_PRAM) -> Self {
         CSEc {
             ftfc: ftfc,
             cse_pram: cse_pram,
+            rng_seeded: Cell::new(false),
         }
     }
 
@@ -263,12 +401,57 @@ impl CSEc {
             Format::Copy,
             Sequence::First,
             KeyID::SecretKey,
-        )
+        )?;
+        self.rng_seeded.set(true);
+        Ok(())
+    }
+
+    /// Power-on self test of the engine, returning the first failure
+    ///
+    /// Seeds the RNG and draws from it, then loads a test key and checks a CBC encryption, the
+    /// decryption back, and MAC generation and verification against known answers. A wrong answer
+    /// fails with `CommandResult::GeneralError`.
+    ///
+    /// The RAM key is overwritten with the test key, load the application key afterwards.
+    pub fn self_test(&self) -> Result<(), CommandResult> {
+        self.init_rng()?;
+        self.generate_rnd()?;
+
+        self.load_plainkey(&SELF_TEST_KEY)?;
+
+        let mut buffer = SELF_TEST_PLAINTEXT;
+        self.encrypt_cbc(&SELF_TEST_INIT_VEC, &mut buffer)?;
+        if buffer != SELF_TEST_CIPHERTEXT {
+            return Err(CommandResult::GeneralError);
+        }
+        self.decrypt_cbc(&SELF_TEST_INIT_VEC, &mut buffer)?;
+        if buffer != SELF_TEST_PLAINTEXT {
+            return Err(CommandResult::GeneralError);
+        }
+
+        let cmac = self.generate_mac(&SELF_TEST_PLAINTEXT)?;
+        if cmac != SELF_TEST_CMAC {
+            return Err(CommandResult::GeneralError);
+        }
+        let mut wrong = cmac;
+        wrong[15] ^= 1;
+        if !self.verify_mac(&SELF_TEST_PLAINTEXT, &cmac)?
+            || self.verify_mac(&SELF_TEST_PLAINTEXT, &wrong)?
+        {
+            return Err(CommandResult::GeneralError);
+        }
+
+        Ok(())
     }
 
     /// Generates a vector of 128 random bits.
-    /// This function must be called after `init_rng`.
+    /// Fails with `CommandResult::RngSeed` without running the command if `init_rng` hasn't been
+    /// called.
     pub fn generate_rnd(&self) -> Result<[u8; 16], CommandResult> {
+        if !self.rng_seeded.get() {
+            return Err(CommandResult::RngSeed);
+        }
+
         self.write_command_header(
             Command::Rng,
             Format::Copy,
@@ -302,7 +485,19 @@ impl CSEc {
         init_vec: &[u8; PAGE_SIZE_IN_BYTES],
         buffer: &mut [u8],
     ) -> Result<(), CommandResult> {
-        self.handle_cbc(Command::EncCbc, init_vec, buffer)
+        self.handle_cbc(Command::EncCbc, init_vec, None, buffer)
+    }
+
+    /// Perform AES-128 encryption in CBC mode of `input`, writing the result to `output`.
+    ///
+    /// `input` and `output` must be of equal length.
+    pub fn encrypt_cbc_to(
+        &self,
+        init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), CommandResult> {
+        self.handle_cbc(Command::EncCbc, init_vec, Some(input), output)
     }
 
     /// Perform in-place AES-128 decryption in CBC mode of the input buffer.
@@ -311,17 +506,54 @@ impl CSEc {
         init_vec: &[u8; PAGE_SIZE_IN_BYTES],
         buffer: &mut [u8],
     ) -> Result<(), CommandResult> {
-        self.handle_cbc(Command::DecCbc, init_vec, buffer)
+        self.handle_cbc(Command::DecCbc, init_vec, None, buffer)
+    }
+
+    /// Perform AES-128 decryption in CBC mode of `input`, writing the result to `output`.
+    ///
+    /// `input` and `output` must be of equal length.
+    pub fn decrypt_cbc_to(
+        &self,
+        init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), CommandResult> {
+        self.handle_cbc(Command::DecCbc, init_vec, Some(input), output)
+    }
+
+    /// Perform AES-128 encryption in CBC mode of `input` into `output`, passing them by address.
+    ///
+    /// Unlike `encrypt_cbc_to` the data isn't copied through `CSE_PRAM` 7 pages at a time, the
+    /// engine reads and writes system RAM directly in a single command. `input` and `output` must
+    /// be of equal length, an integer multiple of 16 bytes, and aligned to `POINTER_ALIGNMENT`.
+    pub fn encrypt_cbc_pointer(
+        &self,
+        init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), CommandResult> {
+        self.handle_cbc_pointer(Command::EncCbc, init_vec, input, output)
+    }
+
+    /// Perform AES-128 decryption in CBC mode of `input` into `output`, passing them by address.
+    ///
+    /// The requirements on `input` and `output` are the same as for `encrypt_cbc_pointer`.
+    pub fn decrypt_cbc_pointer(
+        &self,
+        init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), CommandResult> {
+        self.handle_cbc_pointer(Command::DecCbc, init_vec, input, output)
     }
 
     /// Generate a 128-bit Message Authentication Code for `input`.
+    ///
+    /// Messages longer than the 7 pages of `CSE_PRAM` are fed to the engine in several rounds.
     pub fn generate_mac(&self, message: &[u8]) -> Result<[u8; 16], CommandResult> {
-        if message.len() > u32::max_value() as usize {
-            return Err(CommandResult::GeneralError);
-        }
-
         // Write how long our message is (in bits)
-        self.write_command_words(MAC_MESSAGE_LENGTH_OFFSET, &[(message.len() * 8) as u32]);
+        let message_bits = message_length_in_bits(message.len())?;
+        self.write_command_words(MAC_MESSAGE_LENGTH_OFFSET, &[message_bits]);
 
         fn process_blocks(
             cse: &CSEc,
@@ -355,12 +587,13 @@ impl CSEc {
     /// Verify a message against a 128-bit Message Authentication Code.
     pub fn verify_mac(&self, message: &[u8], cmac: &[u8; 16]) -> Result<bool, CommandResult> {
         // A length of 0 is interpreted by SHE to compare all bits of `mac`.
-        if message.len() == 0 || message.len() > u32::max_value() as usize {
+        if message.len() == 0 {
             return Err(CommandResult::GeneralError);
         }
 
         // Write how long our message is (in bits)
-        self.write_command_words(MAC_MESSAGE_LENGTH_OFFSET, &[(message.len() * 8) as u32]);
+        let message_bits = message_length_in_bits(message.len())?;
+        self.write_command_words(MAC_MESSAGE_LENGTH_OFFSET, &[message_bits]);
 
         // Write the number of bits of the CMAC to be compared
         self.write_command_halfword(MAC_LENGTH_OFFSET, (cmac.len() * 8) as u16);
@@ -379,9 +612,9 @@ impl CSEc {
             // Write our `message` bytes
             cse.write_command_bytes(PAGE_1_OFFSET, &message[..bytes]);
 
-            // Which page is the next, rounded up?
+            // Which page is the next, rounded up? Pages 1 through 7 hold data.
             let next_page = (PAGE_1_OFFSET + bytes + PAGE_SIZE_IN_BYTES - 1) / PAGE_SIZE_IN_BYTES;
-            if !mac_written && next_page < MAX_PAGES {
+            if !mac_written && next_page <= MAX_PAGES {
                 // All data blocks has been written, append the expected CMAC.
                 cse.write_command_bytes(next_page * PAGE_SIZE_IN_BYTES, &cmac);
                 mac_written = true;
@@ -409,14 +642,95 @@ impl CSEc {
         process_blocks(self, message, cmac, Sequence::First, false)
     }
 
+    /// Encrypt and authenticate `plaintext` into `out` as `[IV || ciphertext || MAC]`.
+    ///
+    /// The scheme is encrypt-then-MAC: `plaintext` is encrypted with AES-128-CBC under
+    /// `keys.encryption` and a random IV, then an AES-CMAC under `keys.mac` is computed over the IV
+    /// and the ciphertext. The RAM key slot is the only one supported by this module, so each key
+    /// is loaded into it before it's used, and it holds `keys.mac` afterwards.
+    ///
+    /// `init_rng` must have been called for the IV. `plaintext` must be an integer multiple of 16
+    /// bytes, and `out` must have room for 32 more bytes than that. Returns the number of bytes
+    /// written to `out`.
+    pub fn seal(
+        &self,
+        keys: &SealKeys,
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, CommandResult> {
+        let mac_offset = PAGE_SIZE_IN_BYTES + plaintext.len();
+        let sealed_len = mac_offset + PAGE_SIZE_IN_BYTES;
+        if out.len() < sealed_len {
+            return Err(CommandResult::GeneralError);
+        }
+
+        let init_vec = self.generate_rnd()?;
+        out[..PAGE_SIZE_IN_BYTES].copy_from_slice(&init_vec);
+        self.load_plainkey(&keys.encryption)?;
+        self.encrypt_cbc_to(
+            &init_vec,
+            plaintext,
+            &mut out[PAGE_SIZE_IN_BYTES..mac_offset],
+        )?;
+
+        self.load_plainkey(&keys.mac)?;
+        let cmac = self.generate_mac(&out[..mac_offset])?;
+        out[mac_offset..sealed_len].copy_from_slice(&cmac);
+
+        Ok(sealed_len)
+    }
+
+    /// Authenticate and decrypt a message produced by `seal` into `out`.
+    ///
+    /// `keys` must be the pair the message was sealed with. The MAC is compared in constant time
+    /// before anything is decrypted. Returns the number of plaintext bytes written to `out`, or
+    /// `None` if the message failed authentication. The RAM key slot holds `keys.encryption`
+    /// afterwards if the message was authentic, `keys.mac` otherwise.
+    pub fn open(
+        &self,
+        keys: &SealKeys,
+        sealed: &[u8],
+        out: &mut [u8],
+    ) -> Result<Option<usize>, CommandResult> {
+        if sealed.len() < 2 * PAGE_SIZE_IN_BYTES || sealed.len() % PAGE_SIZE_IN_BYTES != 0 {
+            return Err(CommandResult::GeneralError);
+        }
+
+        let mac_offset = sealed.len() - PAGE_SIZE_IN_BYTES;
+        let plaintext_len = mac_offset - PAGE_SIZE_IN_BYTES;
+        if out.len() < plaintext_len {
+            return Err(CommandResult::GeneralError);
+        }
+
+        self.load_plainkey(&keys.mac)?;
+        let cmac = self.generate_mac(&sealed[..mac_offset])?;
+        if !constant_time_eq(&cmac, &sealed[mac_offset..]) {
+            return Ok(None);
+        }
+
+        let mut init_vec = [0; PAGE_SIZE_IN_BYTES];
+        init_vec.copy_from_slice(&sealed[..PAGE_SIZE_IN_BYTES]);
+        self.load_plainkey(&keys.encryption)?;
+        self.decrypt_cbc_to(
+            &init_vec,
+            &sealed[PAGE_SIZE_IN_BYTES..mac_offset],
+            &mut out[..plaintext_len],
+        )?;
+
+        Ok(Some(plaintext_len))
+    }
+
+    /// Process `input` into `buffer`, or `buffer` in-place if there is no `input`.
     fn handle_cbc(
         &self,
         command: Command,
         init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: Option<&[u8]>,
         buffer: &mut [u8],
     ) -> Result<(), CommandResult> {
         if buffer.len() % 16 != 0
             || (buffer.len() >> BYTES_TO_PAGES_SHIFT) > u16::max_value() as usize
+            || input.map_or(false, |input| input.len() != buffer.len())
         {
             return Err(CommandResult::GeneralError);
         }
@@ -431,6 +745,7 @@ impl CSEc {
 
         fn process_blocks(
             cse: &CSEc,
+            input: Option<&[u8]>,
             buffer: &mut [u8],
             sequence: Sequence,
             command: Command,
@@ -450,23 +765,74 @@ impl CSEc {
 
             // Write our input bytes from `input`, process them, and read the processed bytes into
             // `output`.
-            cse.write_command_bytes(page_offset, &buffer[..bytes]);
+            match input {
+                Some(input) => cse.write_command_bytes(page_offset, &input[..bytes]),
+                None => cse.write_command_bytes(page_offset, &buffer[..bytes]),
+            }
             cse.write_command_header(command, Format::Copy, sequence, KeyID::RamKey)?;
             cse.read_command_bytes(page_offset, &mut buffer[..bytes]);
 
             // Process remaining blocks, if any
             if buffer.len() - bytes != 0 {
-                process_blocks(cse, &mut buffer[bytes..], Sequence::Subsequent, command)
+                process_blocks(
+                    cse,
+                    input.map(|input| &input[bytes..]),
+                    &mut buffer[bytes..],
+                    Sequence::Subsequent,
+                    command,
+                )
             } else {
                 Ok(())
             }
         }
 
-        process_blocks(self, buffer, Sequence::First, command)
+        process_blocks(self, input, buffer, Sequence::First, command)
+    }
+
+    /// Process `input` into `output` with the pointer format, in a single command.
+    fn handle_cbc_pointer(
+        &self,
+        command: Command,
+        init_vec: &[u8; PAGE_SIZE_IN_BYTES],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), CommandResult> {
+        if input.len() != output.len()
+            || input.len() % 16 != 0
+            || (input.len() >> BYTES_TO_PAGES_SHIFT) > u16::max_value() as usize
+            || input.as_ptr() as usize % POINTER_ALIGNMENT != 0
+            || output.as_ptr() as usize % POINTER_ALIGNMENT != 0
+        {
+            return Err(CommandResult::GeneralError);
+        }
+
+        if input.is_empty() {
+            return Ok(());
+        }
+
+        // The caller's IV may be at any address, give the engine an aligned copy
+        let init_vec = AlignedPage(*init_vec);
+
+        self.write_command_halfword(
+            PAGE_LENGTH_OFFSET,
+            (input.len() >> BYTES_TO_PAGES_SHIFT) as u16,
+        );
+        self.write_command_words(POINTER_INIT_VEC_OFFSET, &[init_vec.0.as_ptr() as u32]);
+        self.write_command_words(POINTER_INPUT_OFFSET, &[input.as_ptr() as u32]);
+        self.write_command_words(POINTER_OUTPUT_OFFSET, &[output.as_mut_ptr() as u32]);
+
+        // The engine accesses the buffers behind the compiler's back, make sure `input` and the IV
+        // are written before it starts, and `output` is read only after it's done.
+        compiler_fence(Ordering::SeqCst);
+        let result =
+            self.write_command_header(command, Format::Pointer, Sequence::First, KeyID::RamKey);
+        compiler_fence(Ordering::SeqCst);
+
+        result
     }
 
     /// Writes the command header to `CSE_PRAM`, triggering the CSEc operation.
-    /// Blocks until the operation has finished.
+    /// Blocks until the operation has finished, or fails with `CommandResult::Timeout`.
     fn write_command_header(
         &self,
         cmd: Command,
@@ -493,8 +859,7 @@ impl CSEc {
                 .byte_3().bits(key as u8)
         });
 
-        // Wait until the operation has finished
-        while self.ftfc.fstat.read().ccif().bit_is_clear() {}
+        self.wait_for_completion(COMMAND_TIMEOUT_CYCLES)?;
 
         let status = CommandResult::from_u16(self.read_command_halfword(ERROR_BITS_OFFSET));
         match status {
@@ -503,13 +868,19 @@ impl CSEc {
         }
     }
 
-    /// Write 32-bit words to `CSE_PRAM` starting at an offset.
+    /// Waits until the operation has finished, polling `FSTAT[CCIF]` for at most `cycles` cycles.
+    fn wait_for_completion(&self, cycles: u32) -> Result<(), CommandResult> {
+        timeout::with_timeout(cycles, || self.ftfc.fstat.read().ccif().bit_is_set())
+            .map_err(|_| CommandResult::Timeout)
+    }
+
+    /// Write 32-bit words to consecutive word offsets of `CSE_PRAM`, starting at `offset`.
     fn write_command_words(&self, offset: usize, words: &[u32]) {
         for i in 0..words.len() {
             let upper = ((words[i] & 0xffff0000) >> 16) as u16;
             let lower = ((words[i] & 0xffff) >> 0) as u16;
-            self.write_command_halfword(offset, upper);
-            self.write_command_halfword(offset + 2, lower);
+            self.write_command_halfword(offset + 4 * i, upper);
+            self.write_command_halfword(offset + 4 * i + 2, lower);
         }
     }
 
@@ -692,3 +1063,82 @@ impl CSEc {
         };
     }
 }
+
+/// The CSEc random number generator as a `rand_core::RngCore`
+///
+/// Random bytes are generated 128 bits at a time and handed out until a new batch is needed.
+#[cfg(feature = "rand_core")]
+pub struct CSEcRng<'a> {
+    csec: &'a CSEc,
+    buf: [u8; PAGE_SIZE_IN_BYTES],
+    index: usize,
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> CSEcRng<'a> {
+    /// Seeds the generator with `CSEc::init_rng` before wrapping it.
+    pub fn new(csec: &'a CSEc) -> Result<Self, CommandResult> {
+        csec.init_rng()?;
+        Ok(CSEcRng {
+            csec: csec,
+            buf: [0; PAGE_SIZE_IN_BYTES],
+            index: PAGE_SIZE_IN_BYTES,
+        })
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> rand_core::RngCore for CSEcRng<'a> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).unwrap()
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        for byte in dest.iter_mut() {
+            if self.index == PAGE_SIZE_IN_BYTES {
+                self.buf = self.csec.generate_rnd().map_err(|e| {
+                    // The error code is the `CommandResult` bit, offset into the custom range
+                    let code = rand_core::Error::CUSTOM_START + e as u32;
+                    rand_core::Error::from(core::num::NonZeroU32::new(code).unwrap())
+                })?;
+                self.index = 0;
+            }
+            *byte = self.buf[self.index];
+            self.index += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> rand_core::CryptoRng for CSEcRng<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_length_in_bits_at_the_boundary() {
+        let max_bytes = (u32::max_value() / 8) as usize;
+        assert_eq!(message_length_in_bits(0).ok(), Some(0));
+        assert_eq!(
+            message_length_in_bits(max_bytes).ok(),
+            Some(u32::max_value() - 7)
+        );
+        assert!(message_length_in_bits(max_bytes + 1).is_err());
+    }
+
+    #[test]
+    fn message_length_in_bits_rejects_usize_overflow() {
+        assert!(message_length_in_bits(usize::max_value()).is_err());
+        assert!(message_length_in_bits(usize::max_value() / 8 + 1).is_err());
+    }
+}
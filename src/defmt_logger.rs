@@ -0,0 +1,87 @@
+//! A `defmt` global logger writing the encoded frames to the console
+//!
+//! With `defmt-over-serial` the frames are written to LPUART1 (connected to the OpenSDA chip on
+//! s32k144evb), which must have been initialized by the application, e.g. through
+//! `console::LpuartConsole::init`. With `defmt-over-itm` they are written to ITM stimulus port 0.
+//! Only one of the two features can be enabled.
+//!
+//! The application must link with `-C link-arg=-Tdefmt.x` for the log strings to be placed in the
+//! ELF file, where the host side decoder finds them.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m;
+use defmt;
+#[cfg(feature = "defmt-over-serial")]
+use s32k144;
+
+#[cfg(all(feature = "defmt-over-serial", feature = "defmt-over-itm"))]
+compile_error!("the `defmt-over-serial` and `defmt-over-itm` features can't be enabled together");
+
+#[defmt::global_logger]
+struct Logger;
+
+/// Set while a frame is being written
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether interrupts were enabled before `acquire` disabled them
+static INTERRUPTS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = cortex_m::register::primask::read();
+        cortex_m::interrupt::disable();
+
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        INTERRUPTS_ACTIVE.store(primask.is_active(), Ordering::Relaxed);
+
+        unsafe { ENCODER.start_frame(write_bytes) }
+    }
+
+    unsafe fn flush() {
+        flush_bytes();
+    }
+
+    unsafe fn release() {
+        ENCODER.end_frame(write_bytes);
+        TAKEN.store(false, Ordering::Relaxed);
+
+        if INTERRUPTS_ACTIVE.load(Ordering::Relaxed) {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, write_bytes);
+    }
+}
+
+#[cfg(feature = "defmt-over-serial")]
+fn write_bytes(bytes: &[u8]) {
+    let lpuart = unsafe { &*s32k144::LPUART1::ptr() };
+    for &byte in bytes {
+        while lpuart.stat.read().tdre().is_0() {}
+        lpuart.data.write(|w| unsafe { w.bits(byte as u32) });
+    }
+}
+
+#[cfg(feature = "defmt-over-serial")]
+fn flush_bytes() {
+    let lpuart = unsafe { &*s32k144::LPUART1::ptr() };
+    while lpuart.stat.read().tc().is_0() {}
+}
+
+#[cfg(feature = "defmt-over-itm")]
+fn write_bytes(bytes: &[u8]) {
+    let itm = unsafe {
+        &mut *(cortex_m::peripheral::ITM::ptr() as *mut cortex_m::peripheral::itm::RegisterBlock)
+    };
+    cortex_m::itm::write_all(&mut itm.stim[0], bytes);
+}
+
+#[cfg(feature = "defmt-over-itm")]
+fn flush_bytes() {}
@@ -0,0 +1,171 @@
+//! The enhanced Direct Memory Access (eDMA) SW module
+//!
+//! This consists of the following HW modules
+//!
+//! - eDMA (enhanced Direct Memory Access controller)
+//! - DMAMUX (Direct Memory Access Multiplexer)
+//!
+//! Only continuous (circular) peripheral-to-memory transfers are supported for now. The DMAMUX
+//! clock must be enabled through `pcc::Pcc::enable_dmamux` before a channel is used.
+
+use core::ptr;
+
+use s32k144;
+
+use crate::pcc;
+
+/// Number of eDMA channels available on the s32k144
+pub const CHANNELS: u8 = 16;
+
+/// Offset from the eDMA base address to the Transfer Control Descriptor of channel 0
+const TCD_OFFSET: usize = 0x1000;
+
+/// Size of one Transfer Control Descriptor
+const TCD_SIZE: usize = 0x20;
+
+/// Enables the DMA channel in the `CHCFG` register of the DMAMUX
+const DMAMUX_ENBL: u8 = 1 << 7;
+
+/// Transfer size of 32-bit for `ATTR[SSIZE]` and `ATTR[DSIZE]`
+const ATTR_32BIT: u16 = 0b010 << 8 | 0b010;
+
+/// Request sources that can be routed to a channel through the DMAMUX
+///
+/// See table 22-2 in datasheet for the full list
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Source {
+    /// FlexCAN0 RX FIFO
+    FlexCan0 = 54,
+
+    /// FlexCAN1 RX FIFO
+    FlexCan1 = 55,
+
+    /// FlexCAN2 RX FIFO
+    FlexCan2 = 56,
+}
+
+/// The valid error types for Channel::init()
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The s32k144 only has 16 eDMA channels
+    InvalidChannel,
+
+    /// The DMAMUX clock gate is disabled, see `pcc::Pcc::enable_dmamux`
+    ClockGateDisabled,
+}
+
+/// Memory layout of a Transfer Control Descriptor
+///
+/// See section 21.4.13 in datasheet
+#[repr(C)]
+struct Tcd {
+    saddr: u32,
+    soff: i16,
+    attr: u16,
+    nbytes: u32,
+    slast: i32,
+    daddr: u32,
+    doff: i16,
+    citer: u16,
+    dlast_sga: i32,
+    csr: u16,
+    biter: u16,
+}
+
+/// A single eDMA channel
+pub struct Channel<'a> {
+    dma: &'a s32k144::dma::RegisterBlock,
+    dmamux: &'a s32k144::dmamux::RegisterBlock,
+    number: u8,
+}
+
+impl<'a> Channel<'a> {
+    pub fn init(
+        dma: &'a s32k144::dma::RegisterBlock,
+        dmamux: &'a s32k144::dmamux::RegisterBlock,
+        number: u8,
+    ) -> Result<Self, Error> {
+        if number >= CHANNELS {
+            return Err(Error::InvalidChannel);
+        }
+        // Touching the DMAMUX with the gate disabled would be a bus fault
+        if !pcc::dmamux_enabled() {
+            return Err(Error::ClockGateDisabled);
+        }
+
+        let channel = Channel {
+            dma: dma,
+            dmamux: dmamux,
+            number: number,
+        };
+        channel.stop();
+        Ok(channel)
+    }
+
+    /// Start a continuous transfer of `minor_bytes` from `source_address` into a ring of
+    /// `major_count` slots starting at `destination_address` every time `source` requests it.
+    ///
+    /// The source address is rewound after every request and the destination address is rewound
+    /// when the ring is full, so the transfer never ends until `stop` is called.
+    ///
+    /// This is unsafe since the eDMA will keep writing to `destination_address` until stopped. The
+    /// caller needs to make sure the memory stays valid for that long.
+    pub(crate) unsafe fn start_circular(
+        &self,
+        source: Source,
+        source_address: u32,
+        minor_bytes: u32,
+        destination_address: u32,
+        major_count: u16,
+    ) {
+        self.stop();
+
+        let tcd = self.tcd();
+        ptr::write_volatile(&mut (*tcd).saddr, source_address);
+        ptr::write_volatile(&mut (*tcd).soff, 4);
+        ptr::write_volatile(&mut (*tcd).attr, ATTR_32BIT);
+        ptr::write_volatile(&mut (*tcd).nbytes, minor_bytes);
+        ptr::write_volatile(&mut (*tcd).slast, -(minor_bytes as i32));
+        ptr::write_volatile(&mut (*tcd).daddr, destination_address);
+        ptr::write_volatile(&mut (*tcd).doff, 4);
+        ptr::write_volatile(&mut (*tcd).citer, major_count);
+        ptr::write_volatile(
+            &mut (*tcd).dlast_sga,
+            -((minor_bytes * major_count as u32) as i32),
+        );
+        // DREQ is left cleared so the request stays enabled when the major loop completes
+        ptr::write_volatile(&mut (*tcd).csr, 0);
+        ptr::write_volatile(&mut (*tcd).biter, major_count);
+
+        ptr::write_volatile(self.chcfg(), DMAMUX_ENBL | source as u8);
+        self.dma.serq.write(|w| unsafe { w.bits(self.number) });
+    }
+
+    /// Stop the channel from serving further requests
+    pub fn stop(&self) {
+        self.dma.cerq.write(|w| unsafe { w.bits(self.number) });
+        unsafe { ptr::write_volatile(self.chcfg(), 0) };
+    }
+
+    /// Return how many minor loops have completed in the current major loop
+    pub(crate) fn completed_minor_loops(&self) -> u16 {
+        let tcd = self.tcd();
+        unsafe { ptr::read_volatile(&(*tcd).biter) - ptr::read_volatile(&(*tcd).citer) }
+    }
+
+    fn tcd(&self) -> *mut Tcd {
+        (self.dma as *const _ as usize + TCD_OFFSET + TCD_SIZE * self.number as usize) as *mut Tcd
+    }
+
+    /// The `CHCFG` registers are byte sized, but reversed inside each 32-bit word
+    fn chcfg(&self) -> *mut u8 {
+        let index = (self.number & !0x3) + (3 - (self.number & 0x3));
+        (self.dmamux as *const _ as usize + index as usize) as *mut u8
+    }
+}
+
+impl<'a> Drop for Channel<'a> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
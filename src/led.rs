@@ -5,6 +5,74 @@ extern crate cortex_m;
 use crate::pcc;
 use s32k144;
 
+/// One of the colors of the RGB LED
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// Gamma 2.2 correction, `round(255 * (i / 255)^2.2)`
+#[rustfmt::skip]
+const GAMMA_2_2: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6,
+    6, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 11, 11, 11, 12,
+    12, 13, 13, 13, 14, 14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19,
+    20, 20, 21, 22, 22, 23, 23, 24, 25, 25, 26, 26, 27, 28, 28, 29,
+    30, 30, 31, 32, 33, 33, 34, 35, 35, 36, 37, 38, 39, 39, 40, 41,
+    42, 43, 43, 44, 45, 46, 47, 48, 49, 49, 50, 51, 52, 53, 54, 55,
+    56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71,
+    73, 74, 75, 76, 77, 78, 79, 81, 82, 83, 84, 85, 87, 88, 89, 90,
+    91, 93, 94, 95, 97, 98, 99, 100, 102, 103, 105, 106, 107, 109, 110, 111,
+    113, 114, 116, 117, 119, 120, 121, 123, 124, 126, 127, 129, 130, 132, 133, 135,
+    137, 138, 140, 141, 143, 145, 146, 148, 149, 151, 153, 154, 156, 158, 159, 161,
+    163, 165, 166, 168, 170, 172, 173, 175, 177, 179, 181, 182, 184, 186, 188, 190,
+    192, 194, 196, 197, 199, 201, 203, 205, 207, 209, 211, 213, 215, 217, 219, 221,
+    223, 225, 227, 229, 231, 234, 236, 238, 240, 242, 244, 246, 248, 251, 253, 255,
+];
+
+/// Map a perceived brightness to a duty cycle, so brightness tracks `value` linearly to the eye
+///
+/// The driver only switches the LED fully on or off, this is meant for scaling duty cycles when
+/// dimming it by other means (e.g. a timer driving the pins). Use `value` directly for raw duty.
+pub fn gamma_correct(value: u8) -> u8 {
+    GAMMA_2_2[value as usize]
+}
+
+/// A double-blink "I'm alive" pattern: two short flashes followed by a pause
+///
+/// Each period is split in eighths, the LED is lit during the first and the third. The pattern is a
+/// pure function of a tick counter, so any timebase works.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Heartbeat {
+    pub channel: Channel,
+
+    /// The length of one double-blink, in ticks
+    pub period: u32,
+}
+
+impl Heartbeat {
+    pub fn new(channel: Channel, period: u32) -> Self {
+        Heartbeat {
+            channel: channel,
+            period: period,
+        }
+    }
+
+    /// Return true if the LED should be lit at `tick`
+    pub fn is_lit(&self, tick: u32) -> bool {
+        if self.period == 0 {
+            return false;
+        }
+
+        let phase = u64::from(tick % self.period) * 8 / u64::from(self.period);
+        phase == 0 || phase == 2
+    }
+}
+
 pub struct RgbLed<'a> {
     ptd: &'a s32k144::ptd::RegisterBlock,
     pcc_portd: &'a pcc::PortD<'a>,
@@ -73,5 +141,49 @@ impl<'a> RgbLed<'a> {
         }
     }
 
+    /// Return which channels are lit, in the order of the arguments to `set` (red, blue, green)
+    ///
+    /// The state is read back from the port data output register, so `led.set(r, b, g)` with the
+    /// returned values restores it.
+    pub fn state(&self) -> (bool, bool, bool) {
+        // The LED is lit when the pin is driven low
+        let pdo = self.ptd.pdor.read().pdo().bits();
+        let lit = |pin: u32| pdo & (1 << pin) == 0;
+        (
+            lit(Self::RED_PIN),
+            lit(Self::BLUE_PIN),
+            lit(Self::GREEN_PIN),
+        )
+    }
+
+    /// Toggle a single channel, leaving the others as they are
+    pub fn toggle(&self, channel: Channel) {
+        let pin = Self::pin(channel);
+        self.ptd.ptor.write(|w| unsafe { w.ptto().bits(1 << pin) });
+    }
+
+    /// Light or turn off a single channel, leaving the others as they are
+    pub fn set_channel(&self, channel: Channel, lit: bool) {
+        let pin = Self::pin(channel);
+        if lit {
+            self.ptd.pcor.write(|w| unsafe { w.ptco().bits(1 << pin) });
+        } else {
+            self.ptd.psor.write(|w| unsafe { w.ptso().bits(1 << pin) });
+        }
+    }
+
+    /// Show `heartbeat` at `tick` on its channel, call this with a monotonically increasing tick
+    pub fn heartbeat(&self, heartbeat: &Heartbeat, tick: u32) {
+        self.set_channel(heartbeat.channel, heartbeat.is_lit(tick));
+    }
+
+    fn pin(channel: Channel) -> u32 {
+        match channel {
+            Channel::Red => Self::RED_PIN,
+            Channel::Green => Self::GREEN_PIN,
+            Channel::Blue => Self::BLUE_PIN,
+        }
+    }
+
     pub fn off(&self) {}
 }
@@ -1,21 +1,47 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 extern crate bit_field;
 #[cfg_attr(feature = "itm", macro_use)]
 extern crate cortex_m;
 extern crate cortex_m_rt;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+extern crate embedded_hal;
 extern crate embedded_types;
+#[cfg(feature = "rand_core")]
+extern crate rand_core;
 extern crate s32k144;
 
+pub mod adc;
+pub mod board;
+pub mod button;
 pub mod can;
 pub mod csec;
+pub mod dma;
 pub mod led;
+pub mod log;
+pub mod lpit;
+pub mod lptmr;
 pub mod lpuart;
 pub mod pcc;
+pub mod rcm;
+pub mod rtc;
+pub mod sim;
 pub mod spc;
+pub mod timeout;
+pub mod trgmux;
 pub mod wdog;
 
 pub mod console;
 
-#[cfg(any(feature = "panic-over-itm", feature = "panic-over-serial"))]
+#[cfg(any(feature = "defmt-over-itm", feature = "defmt-over-serial"))]
+mod defmt_logger;
+
+#[cfg(feature = "async")]
+mod waker;
+
+#[cfg(all(
+    not(test),
+    any(feature = "panic-over-itm", feature = "panic-over-serial")
+))]
 mod panic;
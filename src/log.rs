@@ -0,0 +1,161 @@
+//! A minimal logging facade, writing level tagged lines to the console
+//!
+//! The `error!`, `warn!` and `info!` macros take `format!` style arguments:
+//!
+//! ```rust
+//! s32k144evb::warn!("bus off, {} frames dropped", dropped);
+//! ```
+//!
+//! The output is selected with Cargo features, like for the `defmt` logger:
+//! - `log-over-serial` writes to LPUART1 (connected to the OpenSDA chip on s32k144evb), which must
+//! have been initialized by the application, e.g. through `console::LpuartConsole::init`.
+//! - `log-over-itm` writes to ITM stimulus port 0.
+//!
+//! Only one of them can be enabled.
+//!
+//! Records above `MAX_LEVEL` are compiled out, their arguments are type checked but never
+//! evaluated. The level is `Level::Info` unless one of the `log-max-level-warn`,
+//! `log-max-level-error` or `log-max-level-off` features is enabled, e.g. by the application for
+//! its release builds. Without an output feature every record is compiled out.
+//!
+//! A record is written with interrupts disabled, so lines from different contexts don't interleave,
+//! but they can land in the middle of output written through `LpuartConsole`.
+
+use core::fmt::{self, Write};
+use cortex_m;
+#[cfg(feature = "log-over-serial")]
+use s32k144;
+
+#[cfg(all(feature = "log-over-serial", feature = "log-over-itm"))]
+compile_error!("the `log-over-serial` and `log-over-itm` features can't be enabled together");
+
+/// The severity of a record, `Error` being the most severe
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+}
+
+impl Level {
+    /// The tag a record of this level starts with
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+        }
+    }
+}
+
+/// The least severe level that is written, `None` if logging is turned off
+#[cfg(feature = "log-max-level-off")]
+pub const MAX_LEVEL: Option<Level> = None;
+
+/// The least severe level that is written, `None` if logging is turned off
+#[cfg(all(feature = "log-max-level-error", not(feature = "log-max-level-off")))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Error);
+
+/// The least severe level that is written, `None` if logging is turned off
+#[cfg(all(
+    feature = "log-max-level-warn",
+    not(any(feature = "log-max-level-off", feature = "log-max-level-error"))
+))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Warn);
+
+/// The least severe level that is written, `None` if logging is turned off
+#[cfg(not(any(
+    feature = "log-max-level-off",
+    feature = "log-max-level-error",
+    feature = "log-max-level-warn"
+)))]
+pub const MAX_LEVEL: Option<Level> = Some(Level::Info);
+
+/// Return true if records of `level` are written
+///
+/// This is a constant for a given level, so the check and the record are optimized out when it's
+/// false.
+#[inline(always)]
+pub fn enabled(level: Level) -> bool {
+    let has_output = cfg!(any(feature = "log-over-serial", feature = "log-over-itm"));
+    match MAX_LEVEL {
+        Some(max_level) => has_output && level <= max_level,
+        None => false,
+    }
+}
+
+/// Write a record, used by the macros after checking `enabled`
+#[doc(hidden)]
+pub fn write_record(level: Level, args: fmt::Arguments) {
+    cortex_m::interrupt::free(|_| {
+        let mut output = Output;
+        // The output never fails, only a `Display` impl in `args` can
+        let _ = write!(output, "{} ", level.as_str());
+        let _ = output.write_fmt(args);
+        let _ = output.write_str("\n");
+    });
+}
+
+/// The console selected by the `log-over-*` features
+struct Output;
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "log-over-serial")]
+fn write_bytes(bytes: &[u8]) {
+    let lpuart = unsafe { &*s32k144::LPUART1::ptr() };
+    for &byte in bytes {
+        while lpuart.stat.read().tdre().is_0() {}
+        lpuart.data.write(|w| unsafe { w.bits(byte as u32) });
+    }
+}
+
+#[cfg(feature = "log-over-itm")]
+fn write_bytes(bytes: &[u8]) {
+    let itm = unsafe {
+        &mut *(cortex_m::peripheral::ITM::ptr() as *mut cortex_m::peripheral::itm::RegisterBlock)
+    };
+    cortex_m::itm::write_all(&mut itm.stim[0], bytes);
+}
+
+#[cfg(not(any(feature = "log-over-serial", feature = "log-over-itm")))]
+fn write_bytes(_bytes: &[u8]) {}
+
+/// Write a record of `level` if it's enabled, see the `log` module
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write_record($level, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Write a record of `Level::Error`
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Error, $($arg)+)
+    };
+}
+
+/// Write a record of `Level::Warn`
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Warn, $($arg)+)
+    };
+}
+
+/// Write a record of `Level::Info`
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Info, $($arg)+)
+    };
+}
@@ -0,0 +1,120 @@
+//! The Low Power Interrupt Timer (LPIT) SW module
+//!
+//! Provides a blocking delay that doesn't depend on SysTick, for applications where SysTick is
+//! owned by a scheduler (e.g. RTFM). Channel 0 of the LPIT is used.
+
+use bit_field::BitField;
+
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+
+use s32k144;
+
+use crate::{pcc, spc};
+
+/// The valid error types for Delay::init()
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The LPIT functional clock is not running
+    ClockSourceDisabled,
+
+    /// Only `pcc::ClockSource::Soscdiv2` is supported as functional clock for now
+    UnsupportedClockSource,
+}
+
+/// A blocking delay using LPIT channel 0
+pub struct Delay<'a> {
+    lpit: &'a s32k144::lpit0::RegisterBlock,
+    _pcc_lpit: &'a pcc::Lpit<'a>,
+
+    /// Frequency of the functional clock
+    source_frequency: u32,
+}
+
+impl<'a> Delay<'a> {
+    /// Enable the LPIT and use channel 0 as a delay
+    ///
+    /// The LPIT must have been enabled with `pcc::ClockSource::Soscdiv2` as functional clock, the
+    /// frequency of it is taken from `spc`.
+    pub fn init(
+        lpit: &'a s32k144::lpit0::RegisterBlock,
+        spc: &spc::Spc,
+        pcc_lpit: &'a pcc::Lpit<'a>,
+    ) -> Result<Self, Error> {
+        let source_frequency = match pcc_lpit.source() {
+            pcc::ClockSource::Soscdiv2 => spc.soscdiv2_freq().ok_or(Error::ClockSourceDisabled)?,
+            pcc::ClockSource::None => return Err(Error::ClockSourceDisabled),
+            _ => return Err(Error::UnsupportedClockSource),
+        };
+
+        // M_CEN, keep running in debug mode
+        lpit.mcr
+            .write(|w| unsafe { w.bits(0u32.set_bit(0, true).set_bit(3, true).get_bits(0..32)) });
+
+        // 32-bit periodic counter, disabled until a delay is requested
+        lpit.tctrl0.write(|w| unsafe { w.bits(0) });
+
+        Ok(Delay {
+            lpit: lpit,
+            _pcc_lpit: pcc_lpit,
+            source_frequency: source_frequency,
+        })
+    }
+
+    /// Count down `ticks` functional clock cycles
+    fn wait_ticks(&self, ticks: u32) {
+        if ticks == 0 {
+            return;
+        }
+
+        self.lpit.tval0.write(|w| unsafe { w.bits(ticks - 1) });
+        self.lpit.msr.write(|w| unsafe { w.bits(1 << 0) });
+        self.lpit.tctrl0.write(|w| unsafe { w.bits(1 << 0) });
+        while !self.lpit.msr.read().bits().get_bit(0) {}
+        self.lpit.tctrl0.write(|w| unsafe { w.bits(0) });
+        self.lpit.msr.write(|w| unsafe { w.bits(1 << 0) });
+    }
+}
+
+impl<'a> DelayUs<u32> for Delay<'a> {
+    fn delay_us(&mut self, us: u32) {
+        // Rounded up, so a clock that isn't a whole number of MHz never shortens the delay
+        let mut ticks = (u64::from(us) * u64::from(self.source_frequency) + 999_999) / 1_000_000;
+        while ticks > 0 {
+            let chunk = core::cmp::min(ticks, u64::from(u32::max_value()));
+            self.wait_ticks(chunk as u32);
+            ticks -= chunk;
+        }
+    }
+}
+
+impl<'a> DelayUs<u16> for Delay<'a> {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(u32::from(us));
+    }
+}
+
+impl<'a> DelayUs<u8> for Delay<'a> {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(u32::from(us));
+    }
+}
+
+impl<'a> DelayMs<u32> for Delay<'a> {
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1_000u32);
+        }
+    }
+}
+
+impl<'a> DelayMs<u16> for Delay<'a> {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(u32::from(ms));
+    }
+}
+
+impl<'a> DelayMs<u8> for Delay<'a> {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(u32::from(ms));
+    }
+}
@@ -0,0 +1,91 @@
+//! The Low Power Timer (LPTMR) SW module
+//!
+//! Provides a coarse millisecond timebase clocked from the 1 kHz LPO, for timekeeping that keeps
+//! running while the core sleeps.
+//!
+//! Which clocks keep running in which mode:
+//!
+//! | Clock         | RUN/HSRUN | VLPR | STOP | VLPS |
+//! |---------------|-----------|------|------|------|
+//! | SysTick       | yes       | yes  | no   | no   |
+//! | LPIT (SIRC)   | yes       | yes  | no   | if `SIRCLPEN` is set |
+//! | LPTMR (LPO)   | yes       | yes  | yes  | yes  |
+//!
+//! The LPO 1 kHz output is enabled out of reset (`SIM_LPOCLKS[LPO1KCLKEN]`).
+
+use core::cell::Cell;
+
+use bit_field::BitField;
+
+use s32k144;
+
+use crate::pcc;
+
+/// `PSR[PCS]` value selecting the 1 kHz LPO
+const PCS_LPO1K: u32 = 0b01;
+
+/// A free running millisecond counter using LPTMR0
+pub struct Timebase<'a> {
+    lptmr: &'a s32k144::lptmr0::RegisterBlock,
+    _pcc_lptmr0: &'a pcc::Lptmr0<'a>,
+    last_count: Cell<u16>,
+    wraps: Cell<u16>,
+}
+
+impl<'a> Timebase<'a> {
+    /// Start LPTMR0 as a free running counter of the 1 kHz LPO
+    pub fn init(
+        lptmr: &'a s32k144::lptmr0::RegisterBlock,
+        pcc_lptmr0: &'a pcc::Lptmr0<'a>,
+    ) -> Self {
+        // The timer must be disabled while it's configured
+        lptmr.csr.write(|w| unsafe { w.bits(0) });
+
+        // LPO 1 kHz, prescaler bypassed so the counter increments every millisecond
+        lptmr.psr.write(|w| unsafe {
+            w.bits(
+                0u32.set_bits(0..2, PCS_LPO1K)
+                    .set_bit(2, true)
+                    .get_bits(0..32),
+            )
+        });
+        lptmr.cmr.write(|w| unsafe { w.bits(0xffff) });
+
+        // Time counter mode, free running (TFC) so the counter wraps at 0xffff instead of CMR, and
+        // enabled (TEN)
+        lptmr
+            .csr
+            .write(|w| unsafe { w.bits(0u32.set_bit(2, true).set_bit(0, true).get_bits(0..32)) });
+
+        Timebase {
+            lptmr: lptmr,
+            _pcc_lptmr0: pcc_lptmr0,
+            last_count: Cell::new(0),
+            wraps: Cell::new(0),
+        }
+    }
+
+    /// Return the number of milliseconds since `init`
+    ///
+    /// The hardware counter is 16 bits wide, and is extended in software. This needs to be called
+    /// at least once every 65 seconds for the extension to see every wrap. The returned value wraps
+    /// around after about 49 days.
+    pub fn millis(&self) -> u32 {
+        // CNR must be written before it's read, which latches the current count
+        self.lptmr.cnr.write(|w| unsafe { w.bits(0) });
+        let count = self.lptmr.cnr.read().bits() as u16;
+
+        if count < self.last_count.get() {
+            self.wraps.set(self.wraps.get().wrapping_add(1));
+        }
+        self.last_count.set(count);
+
+        (u32::from(self.wraps.get()) << 16) | u32::from(count)
+    }
+}
+
+impl<'a> Drop for Timebase<'a> {
+    fn drop(&mut self) {
+        self.lptmr.csr.write(|w| unsafe { w.bits(0) });
+    }
+}
@@ -1,13 +1,41 @@
 #![allow(dead_code)]
 
 use embedded_types::io::Error as IOError;
+use s32k144;
 use s32k144::lpuart0;
 
-use crate::spc;
+use crate::{pcc, spc};
+
+#[cfg(feature = "async")]
+use crate::waker::{poll_fn, WakerRegistration};
+#[cfg(feature = "async")]
+use core::cell::Cell;
+#[cfg(feature = "async")]
+use core::task::Poll;
+#[cfg(feature = "async")]
+use cortex_m::interrupt::Mutex;
+
+/// Pin mux value (ALT2) for LPUART1_RX on PTC6
+pub const LPUART1_RX_PTC6_ALT: u8 = 0b010;
+
+/// Pin mux value (ALT2) for LPUART1_TX on PTC7
+pub const LPUART1_TX_PTC7_ALT: u8 = 0b010;
 
 #[derive(Copy, Clone, Debug)]
 pub enum UartError {
     UnsatisfiableBaud,
+
+    /// 10 data bits with parity would need an 11-bit character, which the LPUART doesn't support
+    UnsupportedFrameFormat,
+
+    /// The frequency of the functional clock isn't known, see `Lpuart::init_clocked`
+    UnknownClockFrequency,
+
+    /// An address match address doesn't fit in the 10 bit `MATCH` fields, see `ConfigBuilder`
+    UnsupportedAddress,
+
+    /// The LPUART's clock gate is disabled, see `pcc::Pcc::enable_lpuart1`
+    ClockGateDisabled,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -16,6 +44,105 @@ pub struct Config {
     pub data_bits: DataBits,
     pub parity: Parity,
     pub stop_bits: StopBits,
+
+    /// Invert the transmitted signal (`CTRL[TXINV]`), idle becomes logic low
+    pub invert_tx: bool,
+
+    /// Invert the received signal (`STAT[RXINV]`)
+    ///
+    /// Idle line and break detection work on the inverted signal, so with an inverted line idle is
+    /// logic low and a break is a run of logic high.
+    pub invert_rx: bool,
+
+    /// Only receive frames addressed to this node, filtered in hardware
+    pub address_match: Option<AddressMatch>,
+
+    /// How long the line must be idle before `STAT[IDLE]` is set, see `Lpuart::take_idle`
+    pub idle_length: IdleLength,
+
+    /// Raise the LPUART interrupt when the line goes idle (`CTRL[ILIE]`)
+    pub idle_interrupt: bool,
+
+    /// Half-duplex over the TX pin alone (`CTRL[LOOPS]` and `CTRL[RSRC]`)
+    ///
+    /// The receiver is connected to the TX pin, whose direction is switched by `CTRL[TXDIR]`: it's
+    /// driven by `transmit`, and released again by `receive` once the transmitter is idle, or
+    /// explicitly by `Lpuart::release_line`. Only the TX pin needs to be muxed, see
+    /// `configure_single_wire_pin`.
+    ///
+    /// The receiver hears everything sent, so a transmitted frame is read back as an echo before
+    /// the other end's reply. The pin floats while released, so the line needs a pull-up (or a
+    /// pull-down with `invert_tx`). The other end mustn't start replying before the last stop bit
+    /// has left, i.e. before `tx_complete` is true, and polling for that adds up to one call's
+    /// latency to the turnaround. Devices answering within a fraction of a bit time need the
+    /// direction changed from the transmit complete interrupt instead.
+    pub single_wire: bool,
+
+    /// How the baud rate generator settings are chosen when there is more than one candidate
+    pub oversampling: Oversampling,
+
+    /// Drive an RS-485 transceiver's driver enable from the RTS pin (`MODIR[TXRTSE]`)
+    ///
+    /// The hardware asserts RTS one bit time before the start bit and keeps it asserted until the
+    /// FIFO and shift register are empty, so the bus is released right after the last stop bit
+    /// instead of when software gets around to it. The RTS signal has to be muxed to the pin wired
+    /// to DE, see `configure_rs485_pins`.
+    pub rs485_de: Option<DriverEnablePolarity>,
+}
+
+/// The level of the RS-485 driver enable while transmitting (`MODIR[TXRTSPOL]`)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DriverEnablePolarity {
+    /// High while transmitting, for transceivers with an active high DE
+    ActiveHigh,
+
+    /// Low while transmitting
+    ActiveLow,
+}
+
+/// Preference between noise immunity and baud rate accuracy when choosing `BAUD[OSR]`/`BAUD[SBR]`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Oversampling {
+    /// The highest oversampling ratio that hits the baud rate within one count of the ideal
+    /// divisor, for the best noise immunity
+    Max,
+
+    /// The smallest baud rate error at any oversampling ratio, preferring the higher ratio on ties
+    ///
+    /// Fails with `UnsatisfiableBaud` if even the best candidate is off by more than
+    /// `MAX_BAUD_ERROR_PERCENT`.
+    MinError,
+}
+
+/// The largest baud rate error accepted by `Oversampling::MinError`
+pub const MAX_BAUD_ERROR_PERCENT: u32 = 3;
+
+/// Number of idle characters after a stop bit before the line is considered idle (`CTRL[IDLECFG]`)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IdleLength {
+    Chars1 = 0b000,
+    Chars2 = 0b001,
+    Chars4 = 0b010,
+    Chars8 = 0b011,
+    Chars16 = 0b100,
+    Chars32 = 0b101,
+    Chars64 = 0b110,
+    Chars128 = 0b111,
+}
+
+/// Address matching for multidrop (e.g. RS-485) buses
+///
+/// A received word with its most significant bit set (the 9th bit with `DataBits::B9`) is an
+/// address. It is compared against `address` and `second_address`; on a match it and the data
+/// words following it are received, otherwise they are discarded by the receiver until the next
+/// matching address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AddressMatch {
+    /// Matched against by `BAUD[MAEN1]`/`MATCH[MA1]`
+    pub address: u16,
+
+    /// An optional second address, matched against by `BAUD[MAEN2]`/`MATCH[MA2]`
+    pub second_address: Option<u16>,
 }
 
 impl Default for Config {
@@ -25,7 +152,122 @@ impl Default for Config {
             data_bits: DataBits::B8,
             stop_bits: StopBits::B1,
             parity: Parity::N,
+            invert_tx: false,
+            invert_rx: false,
+            address_match: None,
+            idle_length: IdleLength::Chars1,
+            idle_interrupt: false,
+            single_wire: false,
+            oversampling: Oversampling::Max,
+            rs485_de: None,
+        }
+    }
+}
+
+impl Config {
+    /// Start from the default config, see `ConfigBuilder`
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+}
+
+/// Builds a `Config`, checking the frame format when it's built instead of at `init`
+///
+/// ```rust
+/// let config = lpuart::Config::builder()
+///     .baudrate(115_200)
+///     .parity(lpuart::Parity::E)
+///     .build()?;
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn baudrate(mut self, baudrate: u32) -> Self {
+        self.config.baudrate = baudrate;
+        self
+    }
+
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.config.data_bits = data_bits;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.config.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.config.stop_bits = stop_bits;
+        self
+    }
+
+    pub fn invert_tx(mut self, invert_tx: bool) -> Self {
+        self.config.invert_tx = invert_tx;
+        self
+    }
+
+    pub fn invert_rx(mut self, invert_rx: bool) -> Self {
+        self.config.invert_rx = invert_rx;
+        self
+    }
+
+    pub fn address_match(mut self, address_match: Option<AddressMatch>) -> Self {
+        self.config.address_match = address_match;
+        self
+    }
+
+    pub fn idle_length(mut self, idle_length: IdleLength) -> Self {
+        self.config.idle_length = idle_length;
+        self
+    }
+
+    pub fn idle_interrupt(mut self, idle_interrupt: bool) -> Self {
+        self.config.idle_interrupt = idle_interrupt;
+        self
+    }
+
+    pub fn single_wire(mut self, single_wire: bool) -> Self {
+        self.config.single_wire = single_wire;
+        self
+    }
+
+    pub fn oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.config.oversampling = oversampling;
+        self
+    }
+
+    pub fn rs485_de(mut self, rs485_de: Option<DriverEnablePolarity>) -> Self {
+        self.config.rs485_de = rs485_de;
+        self
+    }
+
+    /// Return the config, or an error if it can't work with any clock
+    ///
+    /// Fails with `UnsatisfiableBaud` for a baud rate of 0, `UnsupportedFrameFormat` if the data
+    /// bits and parity don't fit in a character, and `UnsupportedAddress` if an address for
+    /// `address_match` is wider than the `MATCH` register. Whether the baud rate can be made from
+    /// the source clock is only known at `init`.
+    pub fn build(self) -> Result<Config, UartError> {
+        let config = self.config;
+        if config.baudrate == 0 {
+            return Err(UartError::UnsatisfiableBaud);
+        }
+        CharacterFormat::new(config.data_bits, config.parity)?;
+        if let Some(address_match) = config.address_match {
+            let second_address = address_match.second_address.unwrap_or(0);
+            if address_match.address & !MATCH_ADDRESS_MASK != 0
+                || second_address & !MATCH_ADDRESS_MASK != 0
+            {
+                return Err(UartError::UnsupportedAddress);
+            }
         }
+        Ok(config)
     }
 }
 
@@ -50,10 +292,79 @@ pub enum Parity {
     O,
 }
 
+/// The character length bits (`CTRL[M7]`, `CTRL[M]` and `BAUD[M10]`) for a frame format
+///
+/// The parity bit is part of the character, as its most significant bit, so with parity the
+/// character is one bit longer than the data:
+///
+/// | data bits | no parity       | parity          |
+/// |-----------|-----------------|-----------------|
+/// | 7         | 7-bit (`M7`)    | 8-bit           |
+/// | 8         | 8-bit           | 9-bit (`M`)     |
+/// | 9         | 9-bit (`M`)     | 10-bit (`M10`)  |
+/// | 10        | 10-bit (`M10`)  | not supported   |
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct CharacterFormat {
+    m7: bool,
+    m: bool,
+    m10: bool,
+}
+
+impl CharacterFormat {
+    fn new(data_bits: DataBits, parity: Parity) -> Result<Self, UartError> {
+        let parity_bits = if parity == Parity::N { 0 } else { 1 };
+        let character_bits = data_bits as u32 + parity_bits;
+        match character_bits {
+            7 | 8 | 9 | 10 => Ok(CharacterFormat {
+                m7: character_bits == 7,
+                m: character_bits == 9,
+                m10: character_bits == 10,
+            }),
+            _ => Err(UartError::UnsupportedFrameFormat),
+        }
+    }
+}
+
+/// The baud rate generator settings chosen by `Lpuart::init`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UartTiming {
+    /// Oversampling ratio (`BAUD[OSR]` + 1)
+    pub oversampling: u8,
+
+    /// Baud rate modulo divisor (`BAUD[SBR]`)
+    pub divisor: u16,
+
+    /// Sampling on both edges of the baud rate clock, needed for oversampling ratios below 8
+    pub bothedge: bool,
+
+    /// The baud rate resulting from these settings
+    pub actual_baud: u32,
+}
+
+/// Raw register values read by `Lpuart::debug_snapshot`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LpuartDebug {
+    pub baud: u32,
+
+    /// Status, including the receive error flags
+    pub stat: u32,
+    pub ctrl: u32,
+    pub match_: u32,
+    pub fifo: u32,
+
+    /// The number of words in the transmit and receive FIFOs
+    pub water: u32,
+}
+
 pub struct Lpuart<'a> {
     lpuart: &'a lpuart0::RegisterBlock,
     _spc: &'a spc::Spc<'a>,
     config: Config,
+    timing: UartTiming,
+
+    /// Where `transmit_async`/`receive_async` register their tasks, see `set_waker`
+    #[cfg(feature = "async")]
+    waker: Option<&'a LpuartWaker>,
 }
 
 impl<'a> Lpuart<'a> {
@@ -63,18 +374,49 @@ impl<'a> Lpuart<'a> {
         config: Config,
         source_frequency: u32,
     ) -> Result<Lpuart<'a>, UartError> {
+        // Touching the registers with the gate disabled would be a bus fault
+        if !pcc::lpuart_enabled(lpuart) {
+            return Err(UartError::ClockGateDisabled);
+        }
+
         // disable receiver and transmiter
         lpuart
             .ctrl
             .modify(|_r, w| w.te().clear_bit().re().clear_bit());
 
         // TODO: check that divisor is a sensible value
-        let (oversampling_ratio, divisor) = find_decent_div(source_frequency, config.baudrate)?;
+        let (oversampling_ratio, divisor) =
+            find_decent_div(source_frequency, config.baudrate, config.oversampling)?;
+        let format = CharacterFormat::new(config.data_bits, config.parity)?;
         let bothedge = oversampling_ratio < 8;
 
+        let (maen1, maen2, match_value) = match config.address_match {
+            None => (false, false, 0),
+            Some(address_match) => (
+                true,
+                address_match.second_address.is_some(),
+                u32::from(address_match.address & MATCH_ADDRESS_MASK)
+                    | u32::from(address_match.second_address.unwrap_or(0) & MATCH_ADDRESS_MASK)
+                        << 16,
+            ),
+        };
+        lpuart.match_.write(|w| unsafe { w.bits(match_value) });
+
+        // TXRTSE may only be changed while the transmitter is disabled
+        let modir = match config.rs485_de {
+            None => 0,
+            Some(DriverEnablePolarity::ActiveLow) => MODIR_TXRTSE,
+            Some(DriverEnablePolarity::ActiveHigh) => MODIR_TXRTSE | MODIR_TXRTSPOL,
+        };
+        lpuart.modir.write(|w| unsafe { w.bits(modir) });
+
         lpuart.baud.write(|w| unsafe {
-            w.m10()
-                .bit(config.data_bits == DataBits::B10)
+            w.maen1()
+                .bit(maen1)
+                .maen2()
+                .bit(maen2)
+                .m10()
+                .bit(format.m10)
                 .sbns()
                 .bit(config.stop_bits == StopBits::B2)
                 .bothedge()
@@ -87,15 +429,35 @@ impl<'a> Lpuart<'a> {
 
         lpuart.ctrl.write(|w| {
             w.m7()
-                .bit(config.data_bits == DataBits::B7)
+                .bit(format.m7)
                 .m()
-                .bit(config.data_bits == DataBits::B9)
+                .bit(format.m)
                 .pe()
                 .bit(config.parity != Parity::N)
                 .pt()
                 .bit(config.parity == Parity::O)
+                .txinv()
+                .bit(config.invert_tx)
+                .ilt()
+                .bit(true)
+                .idlecfg()
+                .bits(config.idle_length as u8)
+                .ilie()
+                .bit(config.idle_interrupt)
+                .loops()
+                .bit(config.single_wire)
+                .rsrc()
+                .bit(config.single_wire)
+                .txdir()
+                .clear_bit()
         });
 
+        // Don't write back any of the flags, they are cleared by writing 1
+        let rxinv = if config.invert_rx { STAT_RXINV } else { 0 };
+        lpuart
+            .stat
+            .modify(|r, w| unsafe { w.bits((r.bits() & !(STAT_W1C_FLAGS | STAT_RXINV)) | rxinv) });
+
         lpuart.fifo.write(|w| w.txfe()._1().rxfe()._1());
 
         // enable receiver and transmitter
@@ -105,19 +467,164 @@ impl<'a> Lpuart<'a> {
             lpuart: lpuart,
             _spc: spc,
             config: config,
+            timing: UartTiming {
+                oversampling: oversampling_ratio,
+                divisor: divisor,
+                bothedge: bothedge,
+                actual_baud: source_frequency / (oversampling_ratio as u32 * divisor as u32),
+            },
+            #[cfg(feature = "async")]
+            waker: None,
         })
     }
 
+    /// Like `init`, with the functional clock frequency taken from the PCC guard
+    ///
+    /// Fails with `UartError::UnknownClockFrequency` unless the LPUART was enabled with a clock
+    /// source `Spc` knows the frequency of.
+    pub fn init_clocked(
+        lpuart: &'a lpuart0::RegisterBlock,
+        spc: &'a spc::Spc<'a>,
+        config: Config,
+        pcc_lpuart1: &pcc::Lpuart1,
+    ) -> Result<Lpuart<'a>, UartError> {
+        let source_frequency = pcc_lpuart1
+            .frequency()
+            .ok_or(UartError::UnknownClockFrequency)?;
+        Self::init(lpuart, spc, config, source_frequency)
+    }
+
+    /// Return the baud rate generator settings in use
+    pub fn timing(&self) -> UartTiming {
+        self.timing
+    }
+
+    /// Change the baud rate of a running LPUART, see `set_baud`
+    pub fn set_baud(&mut self, baudrate: u32, source_frequency: u32) -> Result<(), UartError> {
+        self.timing = set_baud(
+            self.lpuart,
+            baudrate,
+            source_frequency,
+            self.config.oversampling,
+        )?;
+        self.config.baudrate = baudrate;
+        Ok(())
+    }
+
+    /// Read the key registers, e.g. for printing when the line misbehaves
+    ///
+    /// `DATA` isn't included, since reading it would consume a received word.
+    pub fn debug_snapshot(&self) -> LpuartDebug {
+        LpuartDebug {
+            baud: self.lpuart.baud.read().bits(),
+            stat: self.lpuart.stat.read().bits(),
+            ctrl: self.lpuart.ctrl.read().bits(),
+            match_: self.lpuart.match_.read().bits(),
+            fifo: self.lpuart.fifo.read().bits(),
+            water: self.lpuart.water.read().bits(),
+        }
+    }
+
+    /// Disable the receiver and transmitter and release the register block
+    ///
+    /// `init` can then be run again, e.g. with a new baud rate.
+    pub fn free(self) -> &'a lpuart0::RegisterBlock {
+        // Let the last frame finish shifting out
+        while self.lpuart.stat.read().tc().is_0() {}
+        self.lpuart
+            .ctrl
+            .modify(|_r, w| w.te().clear_bit().re().clear_bit());
+        self.lpuart
+    }
+
+    /// See `tx_complete`
+    pub fn tx_complete(&self) -> bool {
+        tx_complete(self.lpuart)
+    }
+
+    /// See `rx_active`
+    pub fn rx_active(&self) -> bool {
+        rx_active(self.lpuart)
+    }
+
+    /// Enable or disable the interrupt raised while the transmit FIFO has room (`CTRL[TIE]`)
+    pub fn set_transmit_interrupt(&self, enable: bool) {
+        self.lpuart.ctrl.modify(|_, w| w.tie().bit(enable));
+    }
+
+    /// Discard everything in the FIFOs, see `flush_fifos`
+    pub fn flush_fifos(&self) {
+        flush_fifos(self.lpuart);
+    }
+
+    /// Return true if the line has gone idle since the last call, and clear the flag
+    ///
+    /// The flag is set once `config.idle_length` idle characters have followed a received
+    /// character, so it marks the end of a packet on protocols delimited by idle gaps.
+    pub fn take_idle(&self) -> bool {
+        let idle = self.lpuart.stat.read().bits() & STAT_IDLE != 0;
+        if idle {
+            self.lpuart
+                .stat
+                .modify(|r, w| unsafe { w.bits((r.bits() & !STAT_W1C_FLAGS) | STAT_IDLE) });
+        }
+        idle
+    }
+
+    /// Receive a packet delimited by an idle line into `buf`, returning its length
+    ///
+    /// Blocks until a packet has been received and the line has gone idle, or `buf` is full. A
+    /// stale idle flag from before the call is discarded.
+    pub fn receive_packet(&self, buf: &mut [u8]) -> Result<usize, IOError> {
+        self.take_idle();
+
+        let mut len = 0;
+        let mut idle = false;
+        while len < buf.len() {
+            match self.receive() {
+                Ok(byte) => {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                // Only finish once the FIFO has been emptied after the line went idle
+                Err(IOError::BufferExhausted) if idle => break,
+                Err(IOError::BufferExhausted) => idle = len > 0 && self.take_idle(),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(len)
+    }
+
+    /// Stop driving the TX pin in single wire mode, once the transmitter is idle
+    ///
+    /// Blocks until the last stop bit has left the wire. Does nothing unless `config.single_wire`
+    /// is set.
+    pub fn release_line(&self) {
+        if self.config.single_wire {
+            while !self.tx_complete() {}
+            self.lpuart.ctrl.modify(|_, w| w.txdir().clear_bit());
+        }
+    }
+
     pub fn transmit(&self, data: u8) -> Result<(), IOError> {
         if self.lpuart.stat.read().tdre().is_0() {
             Err(IOError::BufferExhausted)
         } else {
-            self.lpuart.data.write(|w| unsafe { w.bits(data as u32) });
+            if self.config.single_wire {
+                self.lpuart.ctrl.modify(|_, w| w.txdir().set_bit());
+            }
+            // With 7 data bits the MSB isn't part of the character
+            let data = u32::from(data) & data_mask(self.config.data_bits);
+            self.lpuart.data.write(|w| unsafe { w.bits(data) });
             Ok(())
         }
     }
 
     pub fn receive(&self) -> Result<u8, IOError> {
+        if self.config.single_wire && self.tx_complete() {
+            self.lpuart.ctrl.modify(|_, w| w.txdir().clear_bit());
+        }
+
         let receive = self.lpuart.data.read();
         if receive.rxempt().bit() {
             Err(IOError::BufferExhausted)
@@ -127,17 +634,352 @@ impl<'a> Lpuart<'a> {
             self.lpuart.stat.modify(|_, w| w.fe()._1());
             Err(IOError::Other)
         } else {
-            Ok(receive.bits() as u8)
+            // Don't return the parity bit as data
+            Ok((receive.bits() & data_mask(self.config.data_bits)) as u8)
+        }
+    }
+}
+
+/// The data bits of a character in `DATA`, excluding the parity bit
+fn data_mask(data_bits: DataBits) -> u32 {
+    (1 << data_bits as u32) - 1
+}
+
+#[cfg(feature = "async")]
+impl<'a> Lpuart<'a> {
+    /// Use `waker` to wake the tasks waiting in `transmit_async`/`receive_async`
+    ///
+    /// The LPUART interrupt handler must call `waker.on_interrupt`. Without a waker the futures are
+    /// polled again right away instead of waiting for an interrupt.
+    pub fn set_waker(&mut self, waker: &'a LpuartWaker) {
+        self.waker = Some(waker);
+    }
+
+    /// Transmit all of `data`, waiting for room in the transmit FIFO
+    pub async fn transmit_async(&self, data: &[u8]) -> Result<(), IOError> {
+        let mut sent = 0;
+        poll_fn(|cx| {
+            while sent < data.len() {
+                match self.transmit(data[sent]) {
+                    Ok(()) => sent += 1,
+                    Err(IOError::BufferExhausted) => {
+                        match self.waker {
+                            Some(waker) => {
+                                waker.tx.register(cx.waker());
+                                self.enable_interrupt(waker, CTRL_TIE);
+                            }
+                            None => cx.waker().wake_by_ref(),
+                        }
+
+                        // Room may have been made before the interrupt was enabled
+                        if self.lpuart.stat.read().tdre().is_0() {
+                            return Poll::Pending;
+                        }
+                    }
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Receive a word like `receive`, waiting for one to arrive
+    pub async fn receive_async(&self) -> Result<u8, IOError> {
+        poll_fn(|cx| match self.receive() {
+            Err(IOError::BufferExhausted) => {
+                match self.waker {
+                    Some(waker) => {
+                        waker.rx.register(cx.waker());
+                        self.enable_interrupt(waker, CTRL_RIE);
+                    }
+                    None => cx.waker().wake_by_ref(),
+                }
+
+                // A word may have arrived before the interrupt was enabled
+                match self.receive() {
+                    Err(IOError::BufferExhausted) => Poll::Pending,
+                    result => Poll::Ready(result),
+                }
+            }
+            result => Poll::Ready(result),
+        })
+        .await
+    }
+
+    /// Set the interrupt enable `bit` in CTRL, recording it in `waker` if it wasn't set already so
+    /// `on_interrupt` clears only the enables set by the futures
+    fn enable_interrupt(&self, waker: &LpuartWaker, bit: u32) {
+        cortex_m::interrupt::free(|cs| {
+            let enabled = waker.enabled.borrow(cs);
+            let ctrl = self.lpuart.ctrl.read().bits();
+            enabled.set(enabled.get() | (bit & !ctrl));
+            self.lpuart.ctrl.write(|w| unsafe { w.bits(ctrl | bit) });
+        });
+    }
+}
+
+/// The tasks waiting in `Lpuart::transmit_async`/`Lpuart::receive_async`, shared with the
+/// interrupt handler
+///
+/// It's `Sync` and has a `const` constructor, so it can be a static the handler reaches, like
+/// `can::CanWaker`:
+///
+/// ```rust
+/// static LPUART1_WAKER: lpuart::LpuartWaker = lpuart::LpuartWaker::new();
+///
+/// #[interrupt]
+/// fn LPUART1_RxTx() {
+///     LPUART1_WAKER.on_interrupt(unsafe { &*s32k144::LPUART1::ptr() });
+/// }
+/// ```
+#[cfg(feature = "async")]
+pub struct LpuartWaker {
+    tx: WakerRegistration,
+    rx: WakerRegistration,
+
+    /// The CTRL interrupt enables set by the futures, e.g. a transmit interrupt enabled for
+    /// `console::WriteMode::Interrupt` isn't in here
+    enabled: Mutex<Cell<u32>>,
+}
+
+#[cfg(feature = "async")]
+impl LpuartWaker {
+    pub const fn new() -> Self {
+        LpuartWaker {
+            tx: WakerRegistration::new(),
+            rx: WakerRegistration::new(),
+            enabled: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Clear the interrupt enables set by the futures and wake the tasks waiting on them
+    ///
+    /// Call this from the interrupt handler of `lpuart`. Enables set by the application, like the
+    /// idle line interrupt or the transmit interrupt of `console::WriteMode::Interrupt`, are left
+    /// as they are.
+    pub fn on_interrupt(&self, lpuart: &lpuart0::RegisterBlock) {
+        cortex_m::interrupt::free(|cs| {
+            let enabled = self.enabled.borrow(cs);
+            lpuart
+                .ctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !enabled.get()) });
+            enabled.set(0);
+        });
+        self.tx.wake();
+        self.rx.wake();
+    }
+}
+
+/// Transmit interrupt enable in the CTRL register
+#[cfg(feature = "async")]
+const CTRL_TIE: u32 = 1 << 23;
+
+/// Receiver interrupt enable in the CTRL register
+#[cfg(feature = "async")]
+const CTRL_RIE: u32 = 1 << 21;
+
+/// Write-1-to-clear flags in the STAT register
+const STAT_W1C_FLAGS: u32 = 0xc01f_c000;
+
+/// The MA1 and MA2 fields of the MATCH register are 10 bits wide
+const MATCH_ADDRESS_MASK: u16 = 0x3ff;
+
+/// Transmitter request-to-send enable in the MODIR register, RTS becomes a driver enable
+const MODIR_TXRTSE: u32 = 1 << 1;
+
+/// Transmitter request-to-send polarity in the MODIR register, set for active high
+const MODIR_TXRTSPOL: u32 = 1 << 2;
+
+/// The receive data inversion bit in the STAT register
+const STAT_RXINV: u32 = 1 << 28;
+
+/// The idle line flag in the STAT register
+const STAT_IDLE: u32 = 1 << 20;
+
+/// The receive error flags in the STAT register (OR, NF, FE and PF)
+const STAT_ERROR_FLAGS: u32 = 0x000f_0000;
+
+/// Change the baud rate of a running LPUART, leaving the frame format and all other settings as
+/// they are
+///
+/// Only `BAUD[OSR]`, `BAUD[SBR]` and `BAUD[BOTHEDGE]` are written. Nothing is disturbed if no
+/// divisor fits `baudrate`, or if it's 0. Otherwise this waits for the transmitter to finish the
+/// frame in progress, then disables the transmitter and receiver while `BAUD` is written, as the
+/// reference manual requires. A frame being received at that moment is lost.
+pub fn set_baud(
+    lpuart: &lpuart0::RegisterBlock,
+    baudrate: u32,
+    source_frequency: u32,
+    oversampling: Oversampling,
+) -> Result<UartTiming, UartError> {
+    if baudrate == 0 {
+        return Err(UartError::UnsatisfiableBaud);
+    }
+    let (oversampling_ratio, divisor) = find_decent_div(source_frequency, baudrate, oversampling)?;
+    let bothedge = oversampling_ratio < 8;
+
+    let ctrl = lpuart.ctrl.read();
+    let (te, re) = (ctrl.te().bit(), ctrl.re().bit());
+
+    while !tx_complete(lpuart) {}
+    lpuart
+        .ctrl
+        .modify(|_r, w| w.te().clear_bit().re().clear_bit());
+    while lpuart.ctrl.read().te().bit() || lpuart.ctrl.read().re().bit() {}
+
+    lpuart.baud.modify(|_, w| unsafe {
+        w.bothedge()
+            .bit(bothedge)
+            .osr()
+            .bits(oversampling_ratio - 1)
+            .sbr()
+            .bits(divisor as u16)
+    });
+
+    lpuart.ctrl.modify(|_r, w| w.te().bit(te).re().bit(re));
+
+    Ok(UartTiming {
+        oversampling: oversampling_ratio,
+        divisor: divisor,
+        bothedge: bothedge,
+        actual_baud: source_frequency / (oversampling_ratio as u32 * divisor as u32),
+    })
+}
+
+/// Discard everything in the transmit and receive FIFOs and clear the receive error flags
+///
+/// Use this to get back to a clean state after a baud rate change or a burst of framing errors.
+/// Data in the transmit FIFO that hasn't been sent yet is lost.
+pub fn flush_fifos(lpuart: &lpuart0::RegisterBlock) {
+    lpuart.fifo.modify(|_, w| w.rxflush()._1().txflush()._1());
+    lpuart
+        .stat
+        .modify(|r, w| unsafe { w.bits((r.bits() & !STAT_W1C_FLAGS) | STAT_ERROR_FLAGS) });
+}
+
+/// Return true once the transmitter is idle, i.e. the last stop bit has left the wire (`STAT[TC]`)
+///
+/// For half-duplex buses, wait for this before releasing the driver enable pin.
+pub fn tx_complete(lpuart: &lpuart0::RegisterBlock) -> bool {
+    lpuart.stat.read().tc().bit()
+}
+
+/// Return true while the receiver is in the middle of a frame (`STAT[RAF]`)
+pub fn rx_active(lpuart: &lpuart0::RegisterBlock) -> bool {
+    lpuart.stat.read().raf().bit()
+}
+
+/// Mux LPUART1 to PTC6 (RX) and PTC7 (TX), these are connected to the OpenSDA chip on s32k144evb
+pub fn configure_lpuart1_pins(portc: &s32k144::portc::RegisterBlock, _pcc_portc: &pcc::PortC) {
+    portc.pcr6.modify(|_, w| w.mux().bits(LPUART1_RX_PTC6_ALT));
+    portc.pcr7.modify(|_, w| w.mux().bits(LPUART1_TX_PTC7_ALT));
+}
+
+/// A port whose clock is enabled, borrowed from its PCC guard
+#[derive(Clone, Copy)]
+pub enum Port<'a> {
+    C(&'a pcc::PortC<'a>),
+    D(&'a pcc::PortD<'a>),
+    E(&'a pcc::PortE<'a>),
+}
+
+/// A pin to mux an LPUART signal to
+#[derive(Clone, Copy)]
+pub struct Pin<'a> {
+    pub port: Port<'a>,
+
+    /// The pin number, i.e. which `PCRn` register of the port
+    pub pcr: u8,
+
+    /// The mux value (`PCR[MUX]`) selecting the LPUART signal on this pin
+    pub alt: u8,
+}
+
+/// The `MUX` field of a `PCRn` register
+const PCR_MUX_MASK: u32 = 0b111 << 8;
+
+/// The write-1-to-clear interrupt status flag of a `PCRn` register
+const PCR_ISF: u32 = 1 << 24;
+
+impl<'a> Pin<'a> {
+    /// PTC6, LPUART1_RX on s32k144evb, connected to the OpenSDA chip
+    pub fn lpuart1_rx_ptc6(pcc_portc: &'a pcc::PortC<'a>) -> Self {
+        Pin {
+            port: Port::C(pcc_portc),
+            pcr: 6,
+            alt: LPUART1_RX_PTC6_ALT,
+        }
+    }
+
+    /// PTC7, LPUART1_TX on s32k144evb, connected to the OpenSDA chip
+    pub fn lpuart1_tx_ptc7(pcc_portc: &'a pcc::PortC<'a>) -> Self {
+        Pin {
+            port: Port::C(pcc_portc),
+            pcr: 7,
+            alt: LPUART1_TX_PTC7_ALT,
         }
     }
+
+    /// Write `alt` to the pin's mux, leaving the rest of `PCRn` as it is
+    ///
+    /// Panics if `pcr` isn't a pin number (0 to 31).
+    pub fn mux(&self) {
+        assert!(self.pcr < 32);
+
+        // All ports have the same layout, with PCR0 to PCR31 at the start of the register block
+        let base = match self.port {
+            Port::C(_) => s32k144::PORTC::ptr() as *mut u32,
+            Port::D(_) => s32k144::PORTD::ptr() as *mut u32,
+            Port::E(_) => s32k144::PORTE::ptr() as *mut u32,
+        };
+
+        // The port clock is enabled since its guard is borrowed. Don't write back the interrupt
+        // flag, it's cleared by writing 1.
+        unsafe {
+            let pcr = base.add(self.pcr as usize);
+            let value = core::ptr::read_volatile(pcr) & !(PCR_MUX_MASK | PCR_ISF);
+            core::ptr::write_volatile(pcr, value | (u32::from(self.alt) << 8) & PCR_MUX_MASK);
+        }
+    }
+}
+
+/// Mux an LPUART's TX and RX signals to arbitrary pins
+pub fn configure_pins(tx: &Pin, rx: &Pin) {
+    tx.mux();
+    rx.mux();
+}
+
+/// Mux only the TX signal, for `Config::single_wire`
+pub fn configure_single_wire_pin(tx: &Pin) {
+    tx.mux();
+}
+
+/// Mux the TX, RX and RTS signals, for `Config::rs485_de`
+///
+/// `de` is the pin wired to the transceiver's DE (and usually its inverted RE), muxed to the
+/// LPUART's RTS signal. No board pin is wired to a transceiver on s32k144evb, so there is no
+/// preset for it.
+pub fn configure_rs485_pins(tx: &Pin, rx: &Pin, de: &Pin) {
+    tx.mux();
+    rx.mux();
+    de.mux();
 }
 
-fn find_decent_div(source: u32, baud: u32) -> Result<(u8, u16), UartError> {
-    const OVERSAMPLING_MIN: u32 = 4;
-    const OVERSAMPLING_MAX: u32 = 32;
+const OVERSAMPLING_MIN: u32 = 4;
+const OVERSAMPLING_MAX: u32 = 32;
+
+const DIV_MIN: u32 = 1;
+const DIV_MAX: u32 = 8191;
 
-    const DIV_MIN: u32 = 1;
-    const DIV_MAX: u32 = 8191;
+fn find_decent_div(
+    source: u32,
+    baud: u32,
+    oversampling: Oversampling,
+) -> Result<(u8, u16), UartError> {
+    if oversampling == Oversampling::MinError {
+        return find_min_error_div(source, baud);
+    }
 
     let ratio = (source + baud / 2) / baud;
     let alternative_ratio = {
@@ -165,3 +1007,82 @@ fn find_decent_div(source: u32, baud: u32) -> Result<(u8, u16), UartError> {
 
     Err(UartError::UnsatisfiableBaud)
 }
+
+/// Try every oversampling ratio with its nearest divisor and keep the one closest to `baud`
+fn find_min_error_div(source: u32, baud: u32) -> Result<(u8, u16), UartError> {
+    // (oversampling, divisor, |source - baud * oversampling * divisor|)
+    let mut best: Option<(u32, u32, u64)> = None;
+
+    for i in (OVERSAMPLING_MIN..OVERSAMPLING_MAX + 1).rev() {
+        let baud_clock = u64::from(baud) * u64::from(i);
+        let divisor = ((u64::from(source) + baud_clock / 2) / baud_clock) as u32;
+        let divisor = divisor.max(DIV_MIN).min(DIV_MAX);
+        let ratio = u64::from(i * divisor);
+        let error = (i64::from(source) - i64::from(baud) * ratio as i64).abs() as u64;
+
+        // The baud rate error is error / ratio, compare the fractions without dividing. Only a
+        // strictly smaller error replaces a higher oversampling ratio.
+        let better = best.map_or(true, |(best_i, best_divisor, best_error)| {
+            error * u64::from(best_i * best_divisor) < best_error * ratio
+        });
+        if better {
+            best = Some((i, divisor, error));
+        }
+    }
+
+    match best {
+        // error / ratio / baud <= MAX_BAUD_ERROR_PERCENT / 100
+        Some((i, divisor, error))
+            if error * 100
+                <= u64::from(MAX_BAUD_ERROR_PERCENT) * u64::from(baud) * u64::from(i * divisor) =>
+        {
+            Ok((i as u8, divisor as u16))
+        }
+        _ => Err(UartError::UnsatisfiableBaud),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(m7: bool, m: bool, m10: bool) -> Option<CharacterFormat> {
+        Some(CharacterFormat {
+            m7: m7,
+            m: m,
+            m10: m10,
+        })
+    }
+
+    #[test]
+    fn character_format_covers_every_data_bits_and_parity() {
+        let cases = [
+            (DataBits::B7, Parity::N, format(true, false, false)),
+            (DataBits::B7, Parity::E, format(false, false, false)),
+            (DataBits::B7, Parity::O, format(false, false, false)),
+            (DataBits::B8, Parity::N, format(false, false, false)),
+            (DataBits::B8, Parity::E, format(false, true, false)),
+            (DataBits::B8, Parity::O, format(false, true, false)),
+            (DataBits::B9, Parity::N, format(false, true, false)),
+            (DataBits::B9, Parity::E, format(false, false, true)),
+            (DataBits::B9, Parity::O, format(false, false, true)),
+            (DataBits::B10, Parity::N, format(false, false, true)),
+            (DataBits::B10, Parity::E, None),
+            (DataBits::B10, Parity::O, None),
+        ];
+        for &(data_bits, parity, expected) in cases.iter() {
+            assert_eq!(
+                CharacterFormat::new(data_bits, parity).ok(),
+                expected,
+                "{:?} {:?}",
+                data_bits,
+                parity
+            );
+        }
+    }
+
+    #[test]
+    fn data_mask_of_7_bit_data_drops_the_parity_bit() {
+        assert_eq!(data_mask(DataBits::B7), 0x7f);
+    }
+}
@@ -1,8 +1,14 @@
 //! With the panic handler being `#[inline(never)]` the symbol `rust_begin_unwind` will be
 //! available to place a breakpoint on to halt when a panic is happening.
+//!
+//! The report is tuned with Cargo features:
+//! - `panic-terse` only reports the location of the panic, leaving out the message.
+//! - `panic-led-red`, `panic-led-green` and `panic-led-blue` light the RGB LED in that color
+//! (several can be combined) to indicate the fault.
 
-use crate::{console, spc};
+use crate::{console, lpuart, spc};
 use core::{
+    fmt,
     panic::PanicInfo,
     sync::atomic::{self, Ordering},
 };
@@ -10,13 +16,61 @@ use cortex_m;
 use embedded_types::io::Write;
 use s32k144;
 
+/// The panic report, as selected by the `panic-terse` feature
+struct Report<'a, 'b>(&'a PanicInfo<'b>);
+
+impl<'a, 'b> fmt::Display for Report<'a, 'b> {
+    #[cfg(feature = "panic-terse")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.location() {
+            Some(location) => write!(f, "panicked at {}", location),
+            None => write!(f, "panicked"),
+        }
+    }
+
+    #[cfg(not(feature = "panic-terse"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Light the RGB LED in the color selected by the `panic-led-*` features
+#[cfg(any(
+    feature = "panic-led-red",
+    feature = "panic-led-green",
+    feature = "panic-led-blue"
+))]
+unsafe fn indicate_panic() {
+    let pcc = crate::pcc::Pcc::init(&*s32k144::PCC::ptr());
+    if let Ok(pcc_portd) = pcc.enable_portd_shared() {
+        let led =
+            crate::led::RgbLed::init(&*s32k144::PTD::ptr(), &*s32k144::PORTD::ptr(), &pcc_portd);
+        led.set(
+            cfg!(feature = "panic-led-red"),
+            cfg!(feature = "panic-led-blue"),
+            cfg!(feature = "panic-led-green"),
+        );
+
+        // The handler never returns, so the clock must stay enabled
+        core::mem::forget(pcc_portd);
+    }
+}
+
+#[cfg(not(any(
+    feature = "panic-led-red",
+    feature = "panic-led-green",
+    feature = "panic-led-blue"
+)))]
+unsafe fn indicate_panic() {}
+
 #[cfg(feature = "panic-over-itm")]
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     cortex_m::interrupt::free(|cs| {
         let itm = ITM.borrow(cs);
-        iprintln!(&itm.stim[0], "{}", info);
+        iprintln!(&itm.stim[0], "{}", Report(info));
+        unsafe { indicate_panic() };
     });
 
     loop {
@@ -44,7 +98,9 @@ fn panic(info: &PanicInfo) -> ! {
         pcc.pcc_portc.modify(|_, w| w.cgc()._1());
         pcc.pcc_portd.modify(|_, w| w.cgc()._1());
 
-        portc.pcr7.modify(|_, w| w.mux()._010());
+        portc
+            .pcr7
+            .modify(|_, w| w.mux().bits(lpuart::LPUART1_TX_PTC7_ALT));
         portc.pcr9.modify(|_, w| w.mux()._000());
         portd.pcr14.modify(|_, w| w.mux()._000());
 
@@ -56,9 +112,18 @@ fn panic(info: &PanicInfo) -> ! {
         )
         .unwrap();
 
-        let mut serial = console::LpuartConsole::init(&*s32k144::LPUART1::ptr(), &spc);
+        // The application may not have enabled LPUART1, or with another source. PCS can only be
+        // changed with the gate disabled.
+        pcc.pcc_lpuart1.modify(|_, w| w.cgc()._0());
+        pcc.pcc_lpuart1
+            .modify(|_, w| w.pcs().bits(u8::from(crate::pcc::ClockSource::Soscdiv2)));
+        pcc.pcc_lpuart1.modify(|_, w| w.cgc()._1());
 
-        writeln!(serial, "{}", info).unwrap();
+        if let Ok(mut serial) = console::LpuartConsole::init(&*s32k144::LPUART1::ptr(), &spc) {
+            serial.set_write_mode(console::WriteMode::Synchronous);
+            writeln!(serial, "{}", Report(info)).unwrap();
+        }
+        indicate_panic();
     });
 
     loop {
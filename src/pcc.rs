@@ -1,14 +1,23 @@
 //! The peripheral clock controller (PCC) SW module
 
+use core::cell::Cell;
+
 use s32k144;
 
+use crate::spc;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     RegisterNotPresent,
+
+    /// The gate is enabled, or held by shared handles
     AlreadyEnabled,
+
+    /// The gate already has the maximum of 255 shared handles
+    TooManyUsers,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 /// Clock source
 ///
 /// Is used on the following peripherals
@@ -17,6 +26,7 @@ pub enum Error {
 /// - FlexIO
 /// - LPI2C
 /// - LPUART
+/// - ADC
 pub enum ClockSource {
     None,
     Soscdiv2,
@@ -39,42 +49,189 @@ impl From<ClockSource> for u8 {
 
 pub struct PortC<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    shared: Option<&'a SharedGate>,
 }
 
 pub struct PortD<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    shared: Option<&'a SharedGate>,
 }
 
 pub struct PortE<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    shared: Option<&'a SharedGate>,
 }
 
 pub struct Lpuart1<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    frequency: Option<u32>,
+}
+
+impl<'a> Lpuart1<'a> {
+    /// The frequency of the functional clock selected for LPUART1, if `Spc` knows it
+    ///
+    /// Only `ClockSource::Soscdiv2` is known for now, see `lpuart::Lpuart::init_clocked`.
+    pub fn frequency(&self) -> Option<u32> {
+        self.frequency
+    }
 }
 
 pub struct Can0<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    frequencies: spc::Frequencies,
+}
+
+impl<'a> Can0<'a> {
+    /// The clock frequencies when the gate was enabled, see `can::Can::init_clocked`
+    pub fn frequencies(&self) -> spc::Frequencies {
+        self.frequencies
+    }
+}
+
+pub struct Dmamux<'a> {
+    pcc: &'a s32k144::pcc::RegisterBlock,
+}
+
+pub struct Adc0<'a> {
+    pcc: &'a s32k144::pcc::RegisterBlock,
+}
+
+pub struct Lpit<'a> {
+    pcc: &'a s32k144::pcc::RegisterBlock,
+    source: ClockSource,
+}
+
+impl<'a> Lpit<'a> {
+    /// The functional clock selected for the LPIT
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+}
+
+pub struct Lptmr0<'a> {
+    pcc: &'a s32k144::pcc::RegisterBlock,
+}
+
+pub struct Rtc<'a> {
+    pcc: &'a s32k144::pcc::RegisterBlock,
+}
+
+/// Return true if the clock gate of the LPUART at `lpuart` is enabled
+///
+/// Accessing a peripheral whose gate is disabled is a bus fault, so drivers that aren't handed a
+/// guard check this before touching their registers. Unlike the guards, this works with a gate
+/// enabled by other means, e.g. by the debugger or a bootloader.
+pub fn lpuart_enabled(lpuart: &s32k144::lpuart0::RegisterBlock) -> bool {
+    let pcc = unsafe { &*s32k144::PCC::ptr() };
+    let lpuart = lpuart as *const _;
+    if lpuart == s32k144::LPUART0::ptr() {
+        pcc.pcc_lpuart0.read().cgc().is_1()
+    } else if lpuart == s32k144::LPUART1::ptr() {
+        pcc.pcc_lpuart1.read().cgc().is_1()
+    } else {
+        pcc.pcc_lpuart2.read().cgc().is_1()
+    }
+}
+
+/// Like `lpuart_enabled`, for the FlexCAN at `can`
+pub fn can_enabled(can: &s32k144::can0::RegisterBlock) -> bool {
+    let pcc = unsafe { &*s32k144::PCC::ptr() };
+    let can = can as *const _;
+    if can == s32k144::CAN0::ptr() {
+        pcc.pcc_flex_can0.read().cgc().is_1()
+    } else if can == s32k144::CAN1::ptr() {
+        pcc.pcc_flex_can1.read().cgc().is_1()
+    } else {
+        pcc.pcc_flex_can2.read().cgc().is_1()
+    }
+}
+
+/// Like `lpuart_enabled`, for the DMAMUX
+pub fn dmamux_enabled() -> bool {
+    let pcc = unsafe { &*s32k144::PCC::ptr() };
+    pcc.pcc_dmamux.read().cgc().is_1()
+}
+
+/// Book keeping for clock gates handed out by the `enable_*_shared` functions
+#[derive(Default)]
+struct SharedGate {
+    users: Cell<u8>,
+
+    /// The gate was enabled by the first shared handle, and is reset when the last one is dropped
+    owned: Cell<bool>,
+}
+
+impl SharedGate {
+    /// Register a new user, returns true if the caller needs to enable the gate
+    fn acquire(&self, already_enabled: bool) -> Result<bool, Error> {
+        let users = self.users.get().checked_add(1).ok_or(Error::TooManyUsers)?;
+        if self.users.get() == 0 {
+            self.owned.set(!already_enabled);
+        }
+        self.users.set(users);
+        Ok(!already_enabled)
+    }
+
+    /// Return true while shared handles exist
+    fn in_use(&self) -> bool {
+        self.users.get() != 0
+    }
+
+    /// Unregister a user, returns true if the caller needs to reset the gate
+    fn release(&self) -> bool {
+        self.users.set(self.users.get() - 1);
+        self.users.get() == 0 && self.owned.get()
+    }
 }
 
 pub struct Pcc<'a> {
     pcc: &'a s32k144::pcc::RegisterBlock,
+    portc_users: SharedGate,
+    portd_users: SharedGate,
+    porte_users: SharedGate,
 }
 
 impl<'a> Pcc<'a> {
     pub fn init(pcc: &'a s32k144::pcc::RegisterBlock) -> Self {
-        Pcc { pcc: pcc }
+        Pcc {
+            pcc: pcc,
+            portc_users: SharedGate::default(),
+            portd_users: SharedGate::default(),
+            porte_users: SharedGate::default(),
+        }
     }
 
     pub fn enable_portc(&self) -> Result<PortC, Error> {
         let reg_value = self.pcc.pcc_portc.read();
         if reg_value.pr().is_0() {
             Err(Error::RegisterNotPresent)
-        } else if reg_value.cgc().is_1() {
+        } else if reg_value.cgc().is_1() || self.portc_users.in_use() {
             Err(Error::AlreadyEnabled)
         } else {
             self.pcc.pcc_portc.modify(|_, w| w.cgc()._1());
-            Ok(PortC { pcc: self.pcc })
+            Ok(PortC {
+                pcc: self.pcc,
+                shared: None,
+            })
+        }
+    }
+
+    /// Like `enable_portc`, but doesn't fail if the clock is already enabled
+    ///
+    /// The clock is disabled when the last shared handle is dropped, but only if it was enabled by
+    /// a shared handle in the first place. `enable_portc` fails while shared handles exist.
+    pub fn enable_portc_shared(&self) -> Result<PortC, Error> {
+        let reg_value = self.pcc.pcc_portc.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else {
+            if self.portc_users.acquire(reg_value.cgc().is_1())? {
+                self.pcc.pcc_portc.modify(|_, w| w.cgc()._1());
+            }
+            Ok(PortC {
+                pcc: self.pcc,
+                shared: Some(&self.portc_users),
+            })
         }
     }
 
@@ -82,11 +239,33 @@ impl<'a> Pcc<'a> {
         let reg_value = self.pcc.pcc_portd.read();
         if reg_value.pr().is_0() {
             Err(Error::RegisterNotPresent)
-        } else if reg_value.cgc().is_1() {
+        } else if reg_value.cgc().is_1() || self.portd_users.in_use() {
             Err(Error::AlreadyEnabled)
         } else {
             self.pcc.pcc_portd.modify(|_, w| w.cgc()._1());
-            Ok(PortD { pcc: self.pcc })
+            Ok(PortD {
+                pcc: self.pcc,
+                shared: None,
+            })
+        }
+    }
+
+    /// Like `enable_portd`, but doesn't fail if the clock is already enabled
+    ///
+    /// The clock is disabled when the last shared handle is dropped, but only if it was enabled by
+    /// a shared handle in the first place. `enable_portd` fails while shared handles exist.
+    pub fn enable_portd_shared(&self) -> Result<PortD, Error> {
+        let reg_value = self.pcc.pcc_portd.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else {
+            if self.portd_users.acquire(reg_value.cgc().is_1())? {
+                self.pcc.pcc_portd.modify(|_, w| w.cgc()._1());
+            }
+            Ok(PortD {
+                pcc: self.pcc,
+                shared: Some(&self.portd_users),
+            })
         }
     }
 
@@ -94,15 +273,41 @@ impl<'a> Pcc<'a> {
         let reg_value = self.pcc.pcc_porte.read();
         if reg_value.pr().is_0() {
             Err(Error::RegisterNotPresent)
-        } else if reg_value.cgc().is_1() {
+        } else if reg_value.cgc().is_1() || self.porte_users.in_use() {
             Err(Error::AlreadyEnabled)
         } else {
             self.pcc.pcc_porte.modify(|_, w| w.cgc()._1());
-            Ok(PortE { pcc: self.pcc })
+            Ok(PortE {
+                pcc: self.pcc,
+                shared: None,
+            })
+        }
+    }
+
+    /// Like `enable_porte`, but doesn't fail if the clock is already enabled
+    ///
+    /// The clock is disabled when the last shared handle is dropped, but only if it was enabled by
+    /// a shared handle in the first place. `enable_porte` fails while shared handles exist.
+    pub fn enable_porte_shared(&self) -> Result<PortE, Error> {
+        let reg_value = self.pcc.pcc_porte.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else {
+            if self.porte_users.acquire(reg_value.cgc().is_1())? {
+                self.pcc.pcc_porte.modify(|_, w| w.cgc()._1());
+            }
+            Ok(PortE {
+                pcc: self.pcc,
+                shared: Some(&self.porte_users),
+            })
         }
     }
 
-    pub fn enable_lpuart1(&self, source: ClockSource) -> Result<Lpuart1, Error> {
+    /// Enable the LPUART1 clock gate, clocked from `source`
+    ///
+    /// The frequency of `source` is looked up in `spc` and kept in the guard, so the driver can be
+    /// initialized without repeating it.
+    pub fn enable_lpuart1(&self, source: ClockSource, spc: &spc::Spc) -> Result<Lpuart1, Error> {
         let reg_value = self.pcc.pcc_lpuart1.read();
         if reg_value.pr().is_0() {
             Err(Error::RegisterNotPresent)
@@ -113,11 +318,23 @@ impl<'a> Pcc<'a> {
                 .pcc_lpuart1
                 .modify(|_, w| w.pcs().bits(u8::from(source)));
             self.pcc.pcc_lpuart1.modify(|_, w| w.cgc()._1());
-            Ok(Lpuart1 { pcc: self.pcc })
+            Ok(Lpuart1 {
+                pcc: self.pcc,
+                frequency: match source {
+                    ClockSource::Soscdiv2 => spc.soscdiv2_freq(),
+                    _ => None,
+                },
+            })
         }
     }
 
-    pub fn enable_can0(&self) -> Result<Can0, Error> {
+    /// Enable the FlexCAN0 clock gate
+    ///
+    /// Unlike e.g. `enable_lpuart1` there is no `ClockSource` to choose, `PCC_FlexCAN0` has no
+    /// `PCS` field. The protocol engine clock is selected by the controller itself
+    /// (`CTRL1[CLKSRC]`), see `can::CanSettings::clock_source`. The frequencies of both candidates
+    /// are taken from `spc` and kept in the guard.
+    pub fn enable_can0(&self, spc: &spc::Spc) -> Result<Can0, Error> {
         let reg_value = self.pcc.pcc_flex_can0.read();
         if reg_value.pr().is_0() {
             Err(Error::RegisterNotPresent)
@@ -125,26 +342,131 @@ impl<'a> Pcc<'a> {
             Err(Error::AlreadyEnabled)
         } else {
             self.pcc.pcc_flex_can0.modify(|_, w| w.cgc()._1());
-            Ok(Can0 { pcc: self.pcc })
+            Ok(Can0 {
+                pcc: self.pcc,
+                frequencies: spc.frequencies(),
+            })
+        }
+    }
+
+    pub fn enable_dmamux(&self) -> Result<Dmamux, Error> {
+        let reg_value = self.pcc.pcc_dmamux.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else if reg_value.cgc().is_1() {
+            Err(Error::AlreadyEnabled)
+        } else {
+            self.pcc.pcc_dmamux.modify(|_, w| w.cgc()._1());
+            Ok(Dmamux { pcc: self.pcc })
+        }
+    }
+
+    pub fn enable_adc0(&self, source: ClockSource) -> Result<Adc0, Error> {
+        let reg_value = self.pcc.pcc_adc0.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else if reg_value.cgc().is_1() {
+            Err(Error::AlreadyEnabled)
+        } else {
+            self.pcc
+                .pcc_adc0
+                .modify(|_, w| w.pcs().bits(u8::from(source)));
+            self.pcc.pcc_adc0.modify(|_, w| w.cgc()._1());
+            Ok(Adc0 { pcc: self.pcc })
+        }
+    }
+
+    pub fn enable_lpit(&self, source: ClockSource) -> Result<Lpit, Error> {
+        let reg_value = self.pcc.pcc_lpit.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else if reg_value.cgc().is_1() {
+            Err(Error::AlreadyEnabled)
+        } else {
+            self.pcc
+                .pcc_lpit
+                .modify(|_, w| w.pcs().bits(u8::from(source)));
+            self.pcc.pcc_lpit.modify(|_, w| w.cgc()._1());
+            Ok(Lpit {
+                pcc: self.pcc,
+                source: source,
+            })
+        }
+    }
+
+    /// Enable the RTC clock gate
+    ///
+    /// This only gates the register interface, the RTC counter clock is selected in the SIM.
+    pub fn enable_rtc(&self) -> Result<Rtc, Error> {
+        let reg_value = self.pcc.pcc_rtc.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else if reg_value.cgc().is_1() {
+            Err(Error::AlreadyEnabled)
+        } else {
+            self.pcc.pcc_rtc.modify(|_, w| w.cgc()._1());
+            Ok(Rtc { pcc: self.pcc })
+        }
+    }
+
+    /// Enable the LPTMR0 clock gate
+    ///
+    /// The LPTMR selects its counter clock itself, so no functional clock is selected here.
+    pub fn enable_lptmr0(&self) -> Result<Lptmr0, Error> {
+        let reg_value = self.pcc.pcc_lptmr0.read();
+        if reg_value.pr().is_0() {
+            Err(Error::RegisterNotPresent)
+        } else if reg_value.cgc().is_1() {
+            Err(Error::AlreadyEnabled)
+        } else {
+            self.pcc.pcc_lptmr0.modify(|_, w| w.cgc()._1());
+            Ok(Lptmr0 { pcc: self.pcc })
         }
     }
 }
 
+/// Implement `leak` for guards, consuming them without running `Drop`
+macro_rules! impl_leak {
+    ($($guard:ident),*) => {
+        $(
+            impl<'a> $guard<'a> {
+                /// Keep the clock gate enabled for the rest of the program
+                ///
+                /// The guard is consumed without resetting the PCC register, so the peripheral
+                /// keeps its clock and configuration. Enabling the gate again fails with
+                /// `Error::AlreadyEnabled`, except through the `enable_*_shared` functions, which
+                /// will then never reset it either.
+                pub fn leak(self) {
+                    core::mem::forget(self);
+                }
+            }
+        )*
+    };
+}
+
+impl_leak!(PortC, PortD, PortE, Lpuart1, Can0, Dmamux, Adc0, Lpit, Rtc, Lptmr0);
+
 impl<'a> Drop for PortC<'a> {
     fn drop(&mut self) {
-        self.pcc.pcc_portc.reset();
+        if self.shared.map_or(true, |gate| gate.release()) {
+            self.pcc.pcc_portc.reset();
+        }
     }
 }
 
 impl<'a> Drop for PortD<'a> {
     fn drop(&mut self) {
-        self.pcc.pcc_portd.reset();
+        if self.shared.map_or(true, |gate| gate.release()) {
+            self.pcc.pcc_portd.reset();
+        }
     }
 }
 
 impl<'a> Drop for PortE<'a> {
     fn drop(&mut self) {
-        self.pcc.pcc_porte.reset();
+        if self.shared.map_or(true, |gate| gate.release()) {
+            self.pcc.pcc_porte.reset();
+        }
     }
 }
 
@@ -159,3 +481,33 @@ impl<'a> Drop for Can0<'a> {
         self.pcc.pcc_flex_can0.reset();
     }
 }
+
+impl<'a> Drop for Dmamux<'a> {
+    fn drop(&mut self) {
+        self.pcc.pcc_dmamux.reset();
+    }
+}
+
+impl<'a> Drop for Adc0<'a> {
+    fn drop(&mut self) {
+        self.pcc.pcc_adc0.reset();
+    }
+}
+
+impl<'a> Drop for Lpit<'a> {
+    fn drop(&mut self) {
+        self.pcc.pcc_lpit.reset();
+    }
+}
+
+impl<'a> Drop for Rtc<'a> {
+    fn drop(&mut self) {
+        self.pcc.pcc_rtc.reset();
+    }
+}
+
+impl<'a> Drop for Lptmr0<'a> {
+    fn drop(&mut self) {
+        self.pcc.pcc_lptmr0.reset();
+    }
+}
@@ -0,0 +1,63 @@
+//! The Reset Control Module (RCM) SW module
+//!
+//! Tells why the chip restarted, e.g. to enter a degraded mode after a watchdog reset.
+
+use bit_field::BitField;
+use s32k144;
+
+/// The sources of the most recent reset, as reported by `RCM_SRS`
+///
+/// More than one source can be set, e.g. a power-on reset also sets `low_voltage`.
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ResetReasons {
+    pub low_voltage: bool,
+    pub loss_of_clock: bool,
+    pub loss_of_lock: bool,
+    pub watchdog: bool,
+
+    /// The external reset pin
+    pub pin: bool,
+    pub power_on: bool,
+    pub jtag: bool,
+
+    /// The core locked up, e.g. on a fault inside the hard fault handler
+    pub core_lockup: bool,
+
+    /// `SCB_AIRCR[SYSRESETREQ]`, e.g. `cortex_m::peripheral::SCB::sys_reset`
+    pub software: bool,
+
+    /// A reset requested by the debugger through the MDM-AP
+    pub debugger: bool,
+
+    /// The stop mode acknowledge timed out
+    pub stop_ack_error: bool,
+}
+
+impl From<u32> for ResetReasons {
+    /// Interpret the value of `RCM_SRS` or `RCM_SSRS`
+    fn from(srs: u32) -> Self {
+        ResetReasons {
+            low_voltage: srs.get_bit(1),
+            loss_of_clock: srs.get_bit(2),
+            loss_of_lock: srs.get_bit(3),
+            watchdog: srs.get_bit(5),
+            pin: srs.get_bit(6),
+            power_on: srs.get_bit(7),
+            jtag: srs.get_bit(8),
+            core_lockup: srs.get_bit(9),
+            software: srs.get_bit(10),
+            debugger: srs.get_bit(11),
+            stop_ack_error: srs.get_bit(13),
+        }
+    }
+}
+
+/// Read the sources of the most recent reset
+pub fn last_reset_reasons(rcm: &s32k144::rcm::RegisterBlock) -> ResetReasons {
+    ResetReasons::from(rcm.srs.read().bits())
+}
+
+/// Return true if the most recent reset was caused by the watchdog
+pub fn reset_was_watchdog(rcm: &s32k144::rcm::RegisterBlock) -> bool {
+    last_reset_reasons(rcm).watchdog
+}
@@ -0,0 +1,109 @@
+//! The Real Time Clock (RTC) SW module
+//!
+//! A 32-bit seconds counter with an alarm that can wake the core from the low-power modes.
+//!
+//! The RTC needs a 32.768 kHz clock for a 1 second tick, selected as `RTC_CLK` by
+//! `SIM_LPOCLKS[RTCSEL]`. The s32k144evb has no 32 kHz crystal, so the clock has to come from the
+//! `RTC_CLKIN` pin or be approximated by the internal LPO 32 kHz output (`ClockSource::Rtc32k` in
+//! both cases). `ClockSource::Lpo1k` uses the LPO 1 kHz directly, which needs no setup but is less
+//! accurate.
+//!
+//! See chapter 46 in datasheet for the full description.
+
+use bit_field::BitField;
+
+use s32k144;
+
+use crate::pcc;
+
+/// The source of the seconds tick
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ClockSource {
+    /// The 32.768 kHz `RTC_CLK` selected by `SIM_LPOCLKS[RTCSEL]`
+    Rtc32k,
+
+    /// The LPO 1 kHz, always running
+    Lpo1k,
+}
+
+/// The RTC instance
+pub struct Rtc<'a> {
+    rtc: &'a s32k144::rtc::RegisterBlock,
+    _pcc_rtc: &'a pcc::Rtc<'a>,
+}
+
+impl<'a> Rtc<'a> {
+    /// Start the seconds counter at 1 with the alarm disabled
+    pub fn init(
+        rtc: &'a s32k144::rtc::RegisterBlock,
+        pcc_rtc: &'a pcc::Rtc<'a>,
+        source: ClockSource,
+    ) -> Self {
+        // The counter must be disabled while it's configured
+        rtc.sr.write(|w| unsafe { w.bits(0) });
+
+        // LPOS
+        rtc.cr.write(|w| unsafe {
+            w.bits(
+                0u32.set_bit(7, source == ClockSource::Lpo1k)
+                    .get_bits(0..32),
+            )
+        });
+
+        // All interrupts are enabled out of reset
+        rtc.ier.write(|w| unsafe { w.bits(0) });
+
+        let rtc = Rtc {
+            rtc: rtc,
+            _pcc_rtc: pcc_rtc,
+        };
+
+        // A seconds value of 0 reads as invalid
+        rtc.set_time(1);
+        rtc
+    }
+
+    /// Set the seconds counter
+    ///
+    /// This also clears the invalid time and overflow flags.
+    pub fn set_time(&self, seconds: u32) {
+        self.rtc.sr.write(|w| unsafe { w.bits(0) });
+        self.rtc.tpr.write(|w| unsafe { w.bits(0) });
+        self.rtc.tsr.write(|w| unsafe { w.bits(seconds) });
+
+        // TCE
+        self.rtc
+            .sr
+            .write(|w| unsafe { w.bits(0u32.set_bit(4, true).get_bits(0..32)) });
+    }
+
+    /// Return the seconds counter
+    pub fn get_time(&self) -> u32 {
+        self.rtc.tsr.read().bits()
+    }
+
+    /// Arm the alarm to go off when the seconds counter reaches `seconds`
+    ///
+    /// The alarm interrupt (`RTC`) is enabled, it must also be unmasked in the NVIC for the alarm
+    /// to wake the core. The alarm flag stays set until `clear_alarm` or `set_alarm` is called.
+    pub fn set_alarm(&self, seconds: u32) {
+        // Writing TAR clears TAF
+        self.rtc.tar.write(|w| unsafe { w.bits(seconds) });
+        self.rtc
+            .ier
+            .modify(|r, w| unsafe { w.bits(r.bits().set_bit(2, true).get_bits(0..32)) });
+    }
+
+    /// Return true if the alarm has gone off
+    pub fn alarm(&self) -> bool {
+        self.rtc.sr.read().bits().get_bit(2)
+    }
+
+    /// Clear the alarm flag and disable the alarm interrupt
+    pub fn clear_alarm(&self) {
+        self.rtc
+            .ier
+            .modify(|r, w| unsafe { w.bits(r.bits().set_bit(2, false).get_bits(0..32)) });
+        self.rtc.tar.write(|w| unsafe { w.bits(0) });
+    }
+}
@@ -0,0 +1,15 @@
+//! The System Integration Module (SIM) SW module
+
+use s32k144;
+
+/// Read the 128-bit unique identification number of the chip
+///
+/// The words are ordered most significant first (`UIDH`, `UIDMH`, `UIDML`, `UIDL`).
+pub fn unique_id(sim: &s32k144::sim::RegisterBlock) -> [u32; 4] {
+    [
+        sim.uidh.read().bits(),
+        sim.uidmh.read().bits(),
+        sim.uidml.read().bits(),
+        sim.uidl.read().bits(),
+    ]
+}
@@ -8,8 +8,21 @@
 
 #![allow(dead_code)]
 
+use core::convert::TryFrom;
+
 use s32k144;
 
+use crate::timeout;
+
+/// Frequency of SIRC in the high range (reset default)
+const SIRC_FREQUENCY: u32 = 8_000_000;
+
+/// Highest `CORE_CLK` frequency allowed in VLPR
+const VLPR_MAX_CORE_FREQUENCY: u32 = 4_000_000;
+
+/// Highest `FLASH_CLK` frequency allowed in VLPR
+const VLPR_MAX_FLASH_FREQUENCY: u32 = 1_000_000;
+
 /// Configurations for the System Clock Generator
 #[derive(Default, Debug, PartialEq, Clone)]
 pub struct Config {
@@ -20,6 +33,9 @@ pub struct Config {
     pub div_core: DivCore,
 
     /// Set the configuration of XTAL and EXTAL pins.
+    ///
+    /// This is independent of `mode`. The oscillator can be running for peripherals (e.g. CAN
+    /// clocked from `soscdiv2`) while the core stays on FIRC.
     pub system_oscillator: SystemOscillatorInput,
 
     /// Set the divider for the soscdiv1_clk
@@ -86,7 +102,11 @@ pub enum Mode {
     /// - The core clock to flash clock ratio is limited to a max value of 8.
     HighSpeed(HighSpeedMode),
 
-    /// Very low power mode
+    /// Very low power run mode
+    ///
+    /// - `CORE_CLK`, `SYS_CLK` and `BUS_CLK` clock frequency must be 4 MHz or less.
+    /// - `FLASH_CLK` clock frequency must be 1 MHz or less.
+    /// - Only SIRC can be running, FIRC, SPLL and the system oscillator are disabled.
     VeryLowPower(VeryLowPowerMode),
 }
 
@@ -96,6 +116,10 @@ impl Default for Mode {
     }
 }
 
+/// A bit pattern that doesn't correspond to any variant, returned by the `TryFrom` conversions
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InvalidEncoding(pub u8);
+
 /// Clock selection modes available in `Mode::Run(_)`
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RunMode {
@@ -112,6 +136,21 @@ pub enum RunMode {
     SPLL,
 }
 
+impl TryFrom<u8> for RunMode {
+    type Error = InvalidEncoding;
+
+    /// Interpret the `SCS` field of `RCCR`
+    fn try_from(scs: u8) -> Result<Self, Self::Error> {
+        match scs {
+            0b0001 => Ok(RunMode::SOSC),
+            0b0010 => Ok(RunMode::SIRC),
+            0b0011 => Ok(RunMode::FIRC),
+            0b0110 => Ok(RunMode::SPLL),
+            _ => Err(InvalidEncoding(scs)),
+        }
+    }
+}
+
 /// Clock selection modes available in `Mode::HighSpeed(_)`
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum HighSpeedMode {
@@ -122,6 +161,19 @@ pub enum HighSpeedMode {
     SPLL,
 }
 
+impl TryFrom<u8> for HighSpeedMode {
+    type Error = InvalidEncoding;
+
+    /// Interpret the `SCS` field of `HCCR`
+    fn try_from(scs: u8) -> Result<Self, Self::Error> {
+        match scs {
+            0b0011 => Ok(HighSpeedMode::FIRC),
+            0b0110 => Ok(HighSpeedMode::SPLL),
+            _ => Err(InvalidEncoding(scs)),
+        }
+    }
+}
+
 /// Clock selection modes available in `Mode::VeryLowPower(_)`
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum VeryLowPowerMode {
@@ -129,6 +181,18 @@ pub enum VeryLowPowerMode {
     SIRC,
 }
 
+impl TryFrom<u8> for VeryLowPowerMode {
+    type Error = InvalidEncoding;
+
+    /// Interpret the `SCS` field of `VCCR`
+    fn try_from(scs: u8) -> Result<Self, Self::Error> {
+        match scs {
+            0b0010 => Ok(VeryLowPowerMode::SIRC),
+            _ => Err(InvalidEncoding(scs)),
+        }
+    }
+}
+
 /// Clock divider for `CORE_CLK` and `SYS_CLK`.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum DivCore {
@@ -184,6 +248,36 @@ impl From<DivCore> for u32 {
     }
 }
 
+impl TryFrom<u8> for DivCore {
+    type Error = InvalidEncoding;
+
+    /// Interpret the `DIVCORE` field of `CSR`, `RCCR`, `VCCR` or `HCCR`
+    ///
+    /// The field holds the divide value minus one, so this is the inverse of
+    /// `u8::from(DivCore) - 1`.
+    fn try_from(divcore: u8) -> Result<Self, Self::Error> {
+        match divcore {
+            0 => Ok(DivCore::Div1),
+            1 => Ok(DivCore::Div2),
+            2 => Ok(DivCore::Div3),
+            3 => Ok(DivCore::Div4),
+            4 => Ok(DivCore::Div5),
+            5 => Ok(DivCore::Div6),
+            6 => Ok(DivCore::Div7),
+            7 => Ok(DivCore::Div8),
+            8 => Ok(DivCore::Div9),
+            9 => Ok(DivCore::Div10),
+            10 => Ok(DivCore::Div11),
+            11 => Ok(DivCore::Div12),
+            12 => Ok(DivCore::Div13),
+            13 => Ok(DivCore::Div14),
+            14 => Ok(DivCore::Div15),
+            15 => Ok(DivCore::Div16),
+            _ => Err(InvalidEncoding(divcore)),
+        }
+    }
+}
+
 /// Clock divider options for system oscillator.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum SystemOscillatorOutput {
@@ -212,6 +306,28 @@ pub enum SystemOscillatorOutput {
     Div64 = 7,
 }
 
+impl SystemOscillatorOutput {
+    /// The divider bringing an `input_hz` oscillator closest to `target_hz` without exceeding it
+    ///
+    /// The smallest divider whose output is at or below the target is returned, e.g. `Div2` for
+    /// 5 MHz from an 8 MHz crystal (4 MHz, as `Div1` gives 8 MHz). `None` if even `Div64` gives
+    /// more than `target_hz`. The result goes in `Config::soscdiv1` or `Config::soscdiv2`.
+    pub fn for_target(input_hz: u32, target_hz: u32) -> Option<SystemOscillatorOutput> {
+        [
+            SystemOscillatorOutput::Div1,
+            SystemOscillatorOutput::Div2,
+            SystemOscillatorOutput::Div4,
+            SystemOscillatorOutput::Div8,
+            SystemOscillatorOutput::Div16,
+            SystemOscillatorOutput::Div32,
+            SystemOscillatorOutput::Div64,
+        ]
+        .iter()
+        .cloned()
+        .find(|&div| input_hz >> (u8::from(div) - 1) <= target_hz)
+    }
+}
+
 impl From<SystemOscillatorOutput> for u8 {
     fn from(div: SystemOscillatorOutput) -> u8 {
         div as u8
@@ -230,24 +346,150 @@ impl From<SystemOscillatorOutput> for isize {
     }
 }
 
+impl TryFrom<u8> for SystemOscillatorOutput {
+    type Error = InvalidEncoding;
+
+    /// Interpret the `SOSCDIV1`/`SOSCDIV2` field of `SOSCDIV`, the inverse of `u8::from`
+    fn try_from(div: u8) -> Result<Self, Self::Error> {
+        match div {
+            0 => Ok(SystemOscillatorOutput::Disable),
+            1 => Ok(SystemOscillatorOutput::Div1),
+            2 => Ok(SystemOscillatorOutput::Div2),
+            3 => Ok(SystemOscillatorOutput::Div4),
+            4 => Ok(SystemOscillatorOutput::Div8),
+            5 => Ok(SystemOscillatorOutput::Div16),
+            6 => Ok(SystemOscillatorOutput::Div32),
+            7 => Ok(SystemOscillatorOutput::Div64),
+            _ => Err(InvalidEncoding(div)),
+        }
+    }
+}
+
 impl Default for SystemOscillatorOutput {
     fn default() -> Self {
         SystemOscillatorOutput::Disable
     }
 }
 
+/// Clock frequencies in Hz, as configured by `Spc::init`
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frequencies {
+    /// Frequency of `CORE_CLK`
+    pub core: u32,
+
+    /// Frequency of `SOSCDIV1_CLK` if running
+    pub soscdiv1: Option<u32>,
+
+    /// Frequency of `SOSCDIV2_CLK` if running
+    pub soscdiv2: Option<u32>,
+}
+
 /// The System Clock Generator instance
+///
+/// The frequency getters are computed from the configuration given to `init`. They don't read any
+/// registers or take any locks, so they can be called from any context, including interrupt
+/// handlers and critical sections.
 pub struct Spc<'a> {
     scg: &'a s32k144::scg::RegisterBlock,
     smc: &'a s32k144::smc::RegisterBlock,
     pmc: &'a s32k144::pmc::RegisterBlock,
     config: Config,
+    frequencies: Frequencies,
 }
 
 /// The valid error types for Pc::init()
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Error {
     NoSystemOscillator,
+
+    /// The system oscillator can't be running in very low power mode
+    SystemOscillatorInVeryLowPower,
+
+    /// `div_core` gives a `CORE_CLK` above what the mode allows
+    CoreClockTooHigh,
+
+    /// A clock or power mode transition didn't complete within `TRANSITION_TIMEOUT_CYCLES` cycles
+    Timeout,
+
+    /// `CSR[SCS]` doesn't show the clock source selected by the mode
+    ClockSourceNotApplied,
+
+    /// `CSR[DIVCORE]` doesn't match `div_core`
+    DividerNotApplied,
+
+    /// `PMSTAT` doesn't show the power mode selected by the mode
+    PowerModeNotApplied,
+
+    /// The mode isn't supported yet: `RunMode::SIRC`, `RunMode::SPLL` and `Mode::HighSpeed(_)`
+    UnsupportedMode,
+
+    /// A system oscillator is configured but `SOSCCSR[SOSCVLD]` isn't set
+    SystemOscillatorNotValid,
+}
+
+/// `PMSTAT` values of the run modes
+const PMSTAT_RUN: u8 = 0b0000_0001;
+const PMSTAT_VLPR: u8 = 0b0000_0100;
+const PMSTAT_HSRUN: u8 = 0b1000_0000;
+
+/// `PMC_LVDSC1`/`PMC_LVDSC2` bits, the flag, acknowledge and interrupt enable are at the same
+/// positions in both
+const LVD_FLAG: u8 = 1 << 7;
+const LVD_ACK: u8 = 1 << 6;
+const LVD_INTERRUPT: u8 = 1 << 5;
+const LVD_RESET: u8 = 1 << 4;
+
+/// How many core cycles a transition may take before it's considered failed
+///
+/// About 200 ms from the 48 MHz FIRC the chip starts on, enough for a slow crystal to start up.
+pub const TRANSITION_TIMEOUT_CYCLES: u32 = 10_000_000;
+
+impl From<timeout::TimeoutError> for Error {
+    fn from(_: timeout::TimeoutError) -> Self {
+        Error::Timeout
+    }
+}
+
+/// Poll `condition` until it holds, or fail with `Error::Timeout`
+fn wait_until<F: Fn() -> bool>(condition: F) -> Result<(), Error> {
+    timeout::with_timeout(TRANSITION_TIMEOUT_CYCLES, condition)?;
+    Ok(())
+}
+
+/// Return true if the system oscillator has the range, gain and dividers `Spc::init` would give
+/// it for `config`
+fn system_oscillator_matches(scg: &s32k144::scg::RegisterBlock, config: &Config) -> bool {
+    let sosccfg = scg.sosccfg.read();
+    let soscdiv = scg.soscdiv.read();
+
+    let input_matches = match config.system_oscillator {
+        SystemOscillatorInput::None => true,
+        SystemOscillatorInput::Crystal(f) => {
+            let range = if f >= 8_000_000 { 0b11 } else { 0b10 };
+            sosccfg.erefs().bit_is_set()
+                && sosccfg.hgo().bit_is_set()
+                && sosccfg.range().bits() == range
+        }
+        // A reference clock has no range or gain
+        SystemOscillatorInput::Reference(_) => true,
+    };
+
+    input_matches
+        && soscdiv.soscdiv1().bits() == u8::from(config.soscdiv1)
+        && soscdiv.soscdiv2().bits() == u8::from(config.soscdiv2)
+}
+
+/// Disable the system oscillator, first moving the core to FIRC if it runs from it
+fn stop_system_oscillator(scg: &s32k144::scg::RegisterBlock) -> Result<(), Error> {
+    if scg.csr.read().scs().bits() == 0b0001 {
+        scg.firccsr.modify(|_, w| w.fircen()._1());
+        wait_until(|| scg.firccsr.read().fircvld().is_1())?;
+        scg.rccr.modify(|_, w| w.scs()._0011());
+        wait_until(|| scg.csr.read().scs().bits() == 0b0011)?;
+    }
+
+    scg.sosccsr.modify(|_, w| w.soscen()._0());
+    wait_until(|| scg.sosccsr.read().soscvld().is_0())
 }
 
 impl<'a> Spc<'a> {
@@ -258,10 +500,43 @@ impl<'a> Spc<'a> {
         pmc: &'a s32k144::pmc::RegisterBlock,
         config: Config,
     ) -> Result<Self, Error> {
-        match config.system_oscillator {
-            SystemOscillatorInput::None => {
-                scg.sosccsr.modify(|_, w| w.soscen()._0());
+        match config.mode {
+            Mode::Run(RunMode::SIRC) | Mode::Run(RunMode::SPLL) | Mode::HighSpeed(_) => {
+                return Err(Error::UnsupportedMode)
+            }
+            _ => (),
+        }
+
+        if let Mode::Run(RunMode::SOSC) = config.mode {
+            if config.system_oscillator == SystemOscillatorInput::None {
+                return Err(Error::NoSystemOscillator);
+            }
+        }
+
+        if let Mode::VeryLowPower(_) = config.mode {
+            if config.system_oscillator != SystemOscillatorInput::None {
+                return Err(Error::SystemOscillatorInVeryLowPower);
+            }
+            if SIRC_FREQUENCY / u32::from(config.div_core) > VLPR_MAX_CORE_FREQUENCY {
+                return Err(Error::CoreClockTooHigh);
             }
+        }
+
+        // An oscillator that is already running as configured is left untouched, so peripherals
+        // clocked from it are not disturbed if the SPC is initialized again (as done by the panic
+        // handler). One running with another range, gain or dividers is stopped and started again.
+        let sosc_running =
+            scg.sosccsr.read().soscvld().is_1() && system_oscillator_matches(scg, &config);
+        if config.system_oscillator != SystemOscillatorInput::None
+            && !sosc_running
+            && scg.sosccsr.read().soscen().is_1()
+        {
+            stop_system_oscillator(scg)?;
+        }
+
+        match config.system_oscillator {
+            SystemOscillatorInput::None => (),
+            _ if sosc_running => (),
             SystemOscillatorInput::Crystal(f) => {
                 scg.sosccfg.modify(|_, w| w.erefs()._1().hgo()._1());
 
@@ -278,12 +553,18 @@ impl<'a> Spc<'a> {
             }
         }
 
-        // TODO: wait untill system oscillator is valid if configured
+        if config.system_oscillator != SystemOscillatorInput::None {
+            wait_until(|| scg.sosccsr.read().soscvld().is_1())?;
+        }
 
-        scg.soscdiv
-            .modify(|_, w| w.soscdiv1().bits(config.soscdiv1.into()));
-        scg.soscdiv
-            .modify(|_, w| w.soscdiv2().bits(config.soscdiv2.into()));
+        // The dividers of a running oscillator already match, they are only written while it's
+        // being started
+        if !sosc_running {
+            scg.soscdiv
+                .modify(|_, w| w.soscdiv1().bits(config.soscdiv1.into()));
+            scg.soscdiv
+                .modify(|_, w| w.soscdiv2().bits(config.soscdiv2.into()));
+        }
 
         // Allowing a transition into HSRUN or VLPR
         smc.pmprot.write(|w| w.ahsrun()._1().avlp()._1());
@@ -295,46 +576,182 @@ impl<'a> Spc<'a> {
                 scg.rccr
                     .modify(|_, w| w.divcore().bits(u8::from(config.div_core) - 1));
                 match mode {
-                    RunMode::SOSC => {
-                        if let SystemOscillatorInput::None = config.system_oscillator {
-                            return Err(Error::NoSystemOscillator);
-                        }
-                        scg.rccr.modify(|_, w| w.scs()._0001());
-                    }
-                    RunMode::SIRC => {
-                        unimplemented!("Mode::Run(RunMode::SIRC) is is not supported yet");
-                        // scg.rccr.modify(|_, w| w.scs()._0010());
-                    }
+                    RunMode::SOSC => scg.rccr.modify(|_, w| w.scs()._0001()),
                     RunMode::FIRC => scg.rccr.modify(|_, w| w.scs()._0011()),
-                    RunMode::SPLL => {
-                        unimplemented!("Mode::Run(RunMode::SPLL) is is not supported yet");
-                        // scg.rccr.modify(|_, w| w.scs()._0110())
-                    }
+                    // Rejected above
+                    RunMode::SIRC | RunMode::SPLL => unreachable!(),
                 }
                 // transition into run mode
                 smc.pmctrl.modify(|_, w| w.runm()._00());
-                while smc.pmstat.read().pmstat().bits() != 0000_001 {}
+                wait_until(|| smc.pmstat.read().pmstat().bits() == PMSTAT_RUN)?;
             }
-            Mode::HighSpeed(_mode) => {
-                // Set the dividers
-                scg.hccr
-                    .modify(|_, w| w.divcore().bits(u8::from(config.div_core)));
-                unimplemented!("High speed more is not supported yet");
-            }
-            Mode::VeryLowPower(_mode) => {
-                // Set the dividers
-                scg.vccr
-                    .modify(|_, w| w.divcore().bits(u8::from(config.div_core)));
-                unimplemented!("Very low power mode is not supported yet");
+            // Rejected above
+            Mode::HighSpeed(_mode) => unreachable!(),
+            Mode::VeryLowPower(VeryLowPowerMode::SIRC) => {
+                // SIRC must be kept running in the VLP modes
+                scg.sirccsr.modify(|_, w| w.sircen()._1().sirclpen()._1());
+                wait_until(|| scg.sirccsr.read().sircvld().is_1())?;
+
+                // Move the core to SIRC while still in RUN, since the other clocks must be
+                // disabled before entering VLPR
+                scg.rccr.modify(|_, w| w.scs()._0010());
+                wait_until(|| scg.csr.read().scs().bits() == 0b0010)?;
+                scg.firccsr.modify(|_, w| w.fircen()._0());
+                scg.spllcsr.modify(|_, w| w.spllen()._0());
+                scg.sosccsr.modify(|_, w| w.soscen()._0());
+                wait_until(|| scg.sosccsr.read().soscvld().is_0())?;
+
+                // Set the dividers, BUS_CLK follows CORE_CLK and FLASH_CLK is divided down to 1 MHz
+                let core_freq = SIRC_FREQUENCY / u32::from(config.div_core);
+                let div_slow =
+                    (core_freq + VLPR_MAX_FLASH_FREQUENCY - 1) / VLPR_MAX_FLASH_FREQUENCY;
+                scg.vccr.modify(|_, w| {
+                    w.scs()
+                        ._0010()
+                        .divcore()
+                        .bits(u8::from(config.div_core) - 1)
+                        .divbus()
+                        .bits(0)
+                        .divslow()
+                        .bits(div_slow as u8 - 1)
+                });
+
+                // Biasing must be enabled for VLPR
+                pmc.regsc.modify(|_, w| w.biasen()._1());
+
+                // transition into very low power run mode
+                smc.pmctrl.modify(|_, w| w.runm()._10());
+                wait_until(|| smc.pmstat.read().pmstat().bits() == PMSTAT_VLPR)?;
             }
         }
 
-        Ok(Spc {
+        // The oscillator can only be disabled once the core has been moved off it, VLPR did that
+        // before the transition
+        if config.system_oscillator == SystemOscillatorInput::None {
+            scg.sosccsr.modify(|_, w| w.soscen()._0());
+        }
+
+        let mut spc = Spc {
             scg: scg,
             smc: smc,
             pmc: pmc,
             config: config,
-        })
+            frequencies: Frequencies::default(),
+        };
+        spc.frequencies = Frequencies {
+            core: spc.core_freq()?,
+            soscdiv1: spc.soscdiv1_freq(),
+            soscdiv2: spc.soscdiv2_freq(),
+        };
+
+        Ok(spc)
+    }
+
+    /// Check that the clock generator and power mode controller are in the state given by the
+    /// configuration
+    ///
+    /// Reads back `CSR[SCS]`, `CSR[DIVCORE]`, `PMSTAT` and `SOSCCSR[SOSCVLD]`, waiting up to
+    /// `TRANSITION_TIMEOUT_CYCLES` cycles for a transition in progress to finish. Returns the error
+    /// for the first mismatch if it doesn't.
+    pub fn verify(&self) -> Result<(), Error> {
+        let mut result = Err(Error::Timeout);
+        let _ = timeout::with_timeout(TRANSITION_TIMEOUT_CYCLES, || {
+            result = self.check_applied();
+            result.is_ok()
+        });
+        result
+    }
+
+    fn check_applied(&self) -> Result<(), Error> {
+        let csr = self.scg.csr.read();
+        let scs = csr.scs().bits();
+        let pmstat = self.smc.pmstat.read().pmstat().bits();
+
+        let (scs_applied, expected_pmstat) = match self.config.mode {
+            Mode::Run(mode) => (RunMode::try_from(scs) == Ok(mode), PMSTAT_RUN),
+            Mode::HighSpeed(mode) => (HighSpeedMode::try_from(scs) == Ok(mode), PMSTAT_HSRUN),
+            Mode::VeryLowPower(mode) => (VeryLowPowerMode::try_from(scs) == Ok(mode), PMSTAT_VLPR),
+        };
+
+        if pmstat != expected_pmstat {
+            Err(Error::PowerModeNotApplied)
+        } else if !scs_applied {
+            Err(Error::ClockSourceNotApplied)
+        } else if DivCore::try_from(csr.divcore().bits()) != Ok(self.config.div_core) {
+            Err(Error::DividerNotApplied)
+        } else if self.config.system_oscillator != SystemOscillatorInput::None
+            && self.scg.sosccsr.read().soscvld().is_0()
+        {
+            Err(Error::SystemOscillatorNotValid)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the clock frequencies computed at `init`
+    ///
+    /// `Frequencies` is `Copy`, so it can be stored in e.g. a static or a task resource and used
+    /// where the `Spc` itself isn't available.
+    pub fn frequencies(&self) -> Frequencies {
+        self.frequencies
+    }
+
+    /// Release the register blocks
+    ///
+    /// The clocks are left running as configured, since the core and peripherals depend on them.
+    /// `init` can then be run again with a new configuration.
+    pub fn free(
+        self,
+    ) -> (
+        &'a s32k144::scg::RegisterBlock,
+        &'a s32k144::smc::RegisterBlock,
+        &'a s32k144::pmc::RegisterBlock,
+    ) {
+        (self.scg, self.smc, self.pmc)
+    }
+
+    /// Configure the low voltage warning (`PMC_LVDSC2`), and clear a pending warning
+    ///
+    /// The warning is raised while the supply is above the low voltage detect (reset) level, giving
+    /// time to save state before the reset. The thresholds are fixed on this chip, see the data
+    /// sheet for their values. With `interrupt` the warning raises the PMC interrupt, which is
+    /// shared with low voltage detect and must also be enabled in the NVIC. Detection only works in
+    /// RUN and HSRUN mode, not in VLPR.
+    pub fn configure_low_voltage_warning(&self, interrupt: bool) {
+        let lvwie = if interrupt { LVD_INTERRUPT } else { 0 };
+        self.pmc
+            .lvdsc2
+            .write(|w| unsafe { w.bits(LVD_ACK | lvwie) });
+    }
+
+    /// Return true if the supply has dropped to the low voltage warning level
+    ///
+    /// The flag stays set until `clear_low_voltage_warning` is called after the supply recovered.
+    pub fn low_voltage_warning(&self) -> bool {
+        self.pmc.lvdsc2.read().bits() & LVD_FLAG != 0
+    }
+
+    /// Acknowledge the low voltage warning, it's raised again if the supply is still low
+    pub fn clear_low_voltage_warning(&self) {
+        self.pmc
+            .lvdsc2
+            .modify(|r, w| unsafe { w.bits(r.bits() | LVD_ACK) });
+    }
+
+    /// Configure low voltage detect (`PMC_LVDSC1`), the level below which the chip isn't safe
+    ///
+    /// `reset` resets the chip on low voltage, as it does out of reset. With `interrupt` the
+    /// detection raises the PMC interrupt instead of or before the reset.
+    ///
+    /// `LVDRE` is write-once: the first call after a power-on reset locks `reset` in, whatever its
+    /// value, and later calls only change `interrupt`. Call it early with the final value of
+    /// `reset`.
+    pub fn configure_low_voltage_detect(&self, reset: bool, interrupt: bool) {
+        let lvdre = if reset { LVD_RESET } else { 0 };
+        let lvdie = if interrupt { LVD_INTERRUPT } else { 0 };
+        self.pmc
+            .lvdsc1
+            .write(|w| unsafe { w.bits(LVD_ACK | lvdie | lvdre) });
     }
 
     /// Return the frequency of socdiv1 clock if running
@@ -361,28 +778,25 @@ impl<'a> Spc<'a> {
         }
     }
 
-    /// Return the frequency of `CORE_CLK` in MHz
-    pub fn core_freq(&self) -> u32 {
-        match self.config.mode {
+    /// Return the frequency of `CORE_CLK` in Hz
+    ///
+    /// Fails with `Error::NoSystemOscillator` if the core is clocked from the system oscillator but
+    /// none is configured, and with `Error::UnsupportedMode` for the modes `init` doesn't support.
+    pub fn core_freq(&self) -> Result<u32, Error> {
+        let freq = match self.config.mode {
             Mode::Run(mode) => match mode {
-                RunMode::SOSC => {
-                    let freq = self.config.system_oscillator.clock_frequency().unwrap();
-                    freq / u32::from(self.config.div_core)
-                }
-                RunMode::SIRC => {
-                    unimplemented!("Mode::Run(RunMode::SIRC) is is not supported yet");
-                }
-                RunMode::FIRC => 48_000_000 / u32::from(self.config.div_core),
-                RunMode::SPLL => {
-                    unimplemented!("Mode::Run(RunMode::SPLL) is is not supported yet");
-                }
+                RunMode::SOSC => self
+                    .config
+                    .system_oscillator
+                    .clock_frequency()
+                    .ok_or(Error::NoSystemOscillator)?,
+                RunMode::SIRC | RunMode::SPLL => return Err(Error::UnsupportedMode),
+                RunMode::FIRC => 48_000_000,
             },
-            Mode::HighSpeed(_mode) => {
-                unimplemented!("High speed more is not supported yet");
-            }
-            Mode::VeryLowPower(_mode) => {
-                unimplemented!("Very low power mode is not supported yet");
-            }
-        }
+            Mode::HighSpeed(_mode) => return Err(Error::UnsupportedMode),
+            Mode::VeryLowPower(VeryLowPowerMode::SIRC) => SIRC_FREQUENCY,
+        };
+
+        Ok(freq / u32::from(self.config.div_core))
     }
 }
@@ -0,0 +1,61 @@
+//! Bounded busy-waiting, so a peripheral that never responds gives an error instead of a hang
+//!
+//! Time is measured with the DWT cycle counter, which counts core clock cycles. It is enabled on
+//! first use if the debugger hasn't done so already.
+
+use core::ptr;
+
+/// `DEMCR`, with `TRCENA` enabling the DWT
+const DEMCR: *mut u32 = 0xe000_edfc as *mut u32;
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+/// `DWT_CTRL`, with `CYCCNTENA` starting the cycle counter
+const DWT_CTRL: *mut u32 = 0xe000_1000 as *mut u32;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+
+const DWT_CYCCNT: *const u32 = 0xe000_1004 as *const u32;
+
+/// The condition didn't become true in time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TimeoutError;
+
+/// Poll `condition` until it returns true, for at most `cycles` core clock cycles
+///
+/// `condition` is polled once more after the time is up, so a slow context switch or interrupt
+/// doesn't cause a spurious timeout. `cycles` must be below 2^31, i.e. about 26 s at 80 MHz.
+pub fn with_timeout<F: FnMut() -> bool>(cycles: u32, mut condition: F) -> Result<(), TimeoutError> {
+    enable_cycle_counter();
+    let start = cycle_count();
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if cycle_count().wrapping_sub(start) > cycles {
+            return if condition() {
+                Ok(())
+            } else {
+                Err(TimeoutError)
+            };
+        }
+    }
+}
+
+/// The current value of the DWT cycle counter
+pub fn cycle_count() -> u32 {
+    unsafe { ptr::read_volatile(DWT_CYCCNT) }
+}
+
+/// Start the DWT cycle counter, leaving it untouched if it's already running
+pub fn enable_cycle_counter() {
+    // The debug registers are always clocked, writing them has no effect on other peripherals
+    unsafe {
+        let demcr = ptr::read_volatile(DEMCR);
+        if demcr & DEMCR_TRCENA == 0 {
+            ptr::write_volatile(DEMCR, demcr | DEMCR_TRCENA);
+        }
+        let ctrl = ptr::read_volatile(DWT_CTRL);
+        if ctrl & DWT_CTRL_CYCCNTENA == 0 {
+            ptr::write_volatile(DWT_CTRL, ctrl | DWT_CTRL_CYCCNTENA);
+        }
+    }
+}
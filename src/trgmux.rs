@@ -0,0 +1,198 @@
+//! The Trigger Multiplexing Control (TRGMUX) SW module
+//!
+//! TRGMUX connects a trigger source (timers, pins, comparator, communication peripherals) to a
+//! trigger input of another peripheral (ADC, FTM, PDB, DMA, ...), so they can be synchronized in
+//! hardware.
+//!
+//! Every target peripheral has one 32-bit register with up to four `SEL` fields, one for each of
+//! its trigger inputs. The `Target` values are encoded as `register index * 4 + SEL field`.
+//!
+//! See chapter 23 in datasheet for the full description.
+
+use core::ptr;
+
+use s32k144;
+
+/// Width of a `SEL` field including the reserved bit after it
+const SEL_FIELD_WIDTH: u32 = 8;
+
+/// Mask of the valid bits in a `SEL` field
+const SEL_MASK: u32 = 0x7f;
+
+/// The lock bit, when set the register is read-only until the next reset
+const LK_BIT: u32 = 1 << 31;
+
+/// Trigger sources
+///
+/// See table 23-1 in datasheet
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Source {
+    /// Logic low, this disconnects the target
+    Disabled = 0,
+    /// Logic high
+    Vdd = 1,
+    TrgmuxIn0 = 2,
+    TrgmuxIn1 = 3,
+    TrgmuxIn2 = 4,
+    TrgmuxIn3 = 5,
+    TrgmuxIn4 = 6,
+    TrgmuxIn5 = 7,
+    TrgmuxIn6 = 8,
+    TrgmuxIn7 = 9,
+    TrgmuxIn8 = 10,
+    TrgmuxIn9 = 11,
+    TrgmuxIn10 = 12,
+    TrgmuxIn11 = 13,
+    Cmp0Out = 14,
+    LpitCh0 = 17,
+    LpitCh1 = 18,
+    LpitCh2 = 19,
+    LpitCh3 = 20,
+    Lptmr0 = 21,
+    Ftm0InitTrig = 22,
+    Ftm0ExtTrig = 23,
+    Ftm1InitTrig = 24,
+    Ftm1ExtTrig = 25,
+    Ftm2InitTrig = 26,
+    Ftm2ExtTrig = 27,
+    Ftm3InitTrig = 28,
+    Ftm3ExtTrig = 29,
+    Adc0Sc1aCoco = 30,
+    Adc0Sc1bCoco = 31,
+    Adc1Sc1aCoco = 32,
+    Adc1Sc1bCoco = 33,
+    Pdb0Ch0Trig = 34,
+    Pdb0PulseOut = 36,
+    Pdb1Ch0Trig = 37,
+    Pdb1PulseOut = 39,
+    RtcAlarm = 43,
+    RtcSecond = 44,
+    FlexioTrig0 = 45,
+    FlexioTrig1 = 46,
+    FlexioTrig2 = 47,
+    FlexioTrig3 = 48,
+    Lpuart0RxData = 49,
+    Lpuart0TxData = 50,
+    Lpuart0RxIdle = 51,
+    Lpuart1RxData = 52,
+    Lpuart1TxData = 53,
+    Lpuart1RxIdle = 54,
+    Lpi2c0MasterTrig = 55,
+    Lpi2c0SlaveTrig = 56,
+    Lpspi0Frame = 59,
+    Lpspi0RxData = 60,
+    Lpspi1Frame = 61,
+    Lpspi1RxData = 62,
+    /// Software trigger through `SIM_MISCTRL1[SW_TRG]`
+    SimSwTrig = 63,
+}
+
+/// Trigger targets
+///
+/// See table 23-2 in datasheet
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Target {
+    DmaCh0 = 0,
+    DmaCh1 = 1,
+    DmaCh2 = 2,
+    DmaCh3 = 3,
+    TrgmuxOut0 = 4,
+    TrgmuxOut1 = 5,
+    TrgmuxOut2 = 6,
+    TrgmuxOut3 = 7,
+    TrgmuxOut4 = 8,
+    TrgmuxOut5 = 9,
+    TrgmuxOut6 = 10,
+    TrgmuxOut7 = 11,
+    /// ADC0 hardware trigger, used with `adc::Trigger::Trgmux`
+    Adc0Tla0 = 12,
+    Adc0Tla1 = 13,
+    Adc0Tla2 = 14,
+    Adc0Tla3 = 15,
+    Adc1Tla0 = 16,
+    Adc1Tla1 = 17,
+    Adc1Tla2 = 18,
+    Adc1Tla3 = 19,
+    Cmp0SampleInput = 28,
+    Ftm0HwTrig0 = 40,
+    Ftm0Fault0 = 41,
+    Ftm0Fault1 = 42,
+    Ftm0Fault2 = 43,
+    Ftm1HwTrig0 = 44,
+    Ftm1Fault0 = 45,
+    Ftm1Fault1 = 46,
+    Ftm1Fault2 = 47,
+    Ftm2HwTrig0 = 48,
+    Ftm2Fault0 = 49,
+    Ftm2Fault1 = 50,
+    Ftm2Fault2 = 51,
+    Ftm3HwTrig0 = 52,
+    Ftm3Fault0 = 53,
+    Ftm3Fault1 = 54,
+    Ftm3Fault2 = 55,
+    Pdb0TrgIn = 56,
+    Pdb1TrgIn = 64,
+    FlexioTrgTim0 = 72,
+    FlexioTrgTim1 = 73,
+    FlexioTrgTim2 = 74,
+    FlexioTrgTim3 = 75,
+    LpitTrgCh0 = 76,
+    LpitTrgCh1 = 77,
+    LpitTrgCh2 = 78,
+    LpitTrgCh3 = 79,
+    Lpuart0Trg = 80,
+    Lpuart1Trg = 84,
+    Lpi2c0Trg = 88,
+    Lpspi0Trg = 96,
+    Lpspi1Trg = 100,
+    Lptmr0Alt0 = 104,
+}
+
+/// The valid error types for the TRGMUX
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The target register has been locked and can't be changed until the next reset
+    Locked,
+}
+
+/// The Trigger MUX instance
+pub struct Trgmux<'a> {
+    trgmux: &'a s32k144::trgmux::RegisterBlock,
+}
+
+impl<'a> Trgmux<'a> {
+    pub fn init(trgmux: &'a s32k144::trgmux::RegisterBlock) -> Self {
+        Trgmux { trgmux: trgmux }
+    }
+
+    /// Connect `source` to `target`, replacing whatever was connected before
+    pub fn connect(&self, source: Source, target: Target) -> Result<(), Error> {
+        let shift = (target as u32 & 0x3) * SEL_FIELD_WIDTH;
+        let register = self.register(target);
+
+        let value = unsafe { ptr::read_volatile(register) };
+        if value & LK_BIT != 0 {
+            return Err(Error::Locked);
+        }
+
+        let value = (value & !(SEL_MASK << shift)) | ((source as u32) << shift);
+        unsafe { ptr::write_volatile(register, value) };
+        Ok(())
+    }
+
+    /// Disconnect `target` from any source
+    pub fn disconnect(&self, target: Target) -> Result<(), Error> {
+        self.connect(Source::Disabled, target)
+    }
+
+    /// Lock the register of `target` (and the other targets sharing it) until the next reset
+    pub fn lock(&self, target: Target) {
+        let register = self.register(target);
+        unsafe { ptr::write_volatile(register, ptr::read_volatile(register) | LK_BIT) };
+    }
+
+    fn register(&self, target: Target) -> *mut u32 {
+        let offset = target as usize & !0x3;
+        (self.trgmux as *const _ as usize + offset) as *mut u32
+    }
+}
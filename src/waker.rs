@@ -0,0 +1,57 @@
+//! Waker storage for the `async` feature
+//!
+//! A future registers its waker before enabling the peripheral interrupt, the `on_interrupt` of the
+//! driver's waker handle masks the interrupt again and wakes it.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use cortex_m::interrupt::{self, Mutex};
+
+/// A waker shared between a future and an interrupt handler
+pub(crate) struct WakerRegistration {
+    waker: Mutex<RefCell<Option<Waker>>>,
+}
+
+impl WakerRegistration {
+    pub(crate) const fn new() -> Self {
+        WakerRegistration {
+            waker: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Store `waker`, replacing the previous one unless they wake the same task
+    pub(crate) fn register(&self, waker: &Waker) {
+        interrupt::free(|cs| {
+            let mut slot = self.waker.borrow(cs).borrow_mut();
+            match *slot {
+                Some(ref registered) if registered.will_wake(waker) => (),
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    /// Wake the registered task, if any
+    pub(crate) fn wake(&self) {
+        if let Some(waker) = interrupt::free(|cs| self.waker.borrow(cs).borrow_mut().take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// A future polling a closure
+pub(crate) struct PollFn<F>(F);
+
+impl<T, F: FnMut(&mut Context) -> Poll<T> + Unpin> Future for PollFn<F> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        (self.get_mut().0)(cx)
+    }
+}
+
+pub(crate) fn poll_fn<T, F: FnMut(&mut Context) -> Poll<T> + Unpin>(f: F) -> PollFn<F> {
+    PollFn(f)
+}
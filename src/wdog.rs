@@ -93,6 +93,26 @@ impl<'a> Watchdog<'a> {
         Ok(watchdog)
     }
 
+    /// Disables the watchdog
+    ///
+    /// Like `init`, this needs to be called within 128 cycles of startup. Updates are left allowed,
+    /// so the watchdog can be configured again later through the returned handle. An error is
+    /// returned if the watchdog is still enabled afterwards.
+    pub fn disable(wdog: &'a s32k144::wdog::RegisterBlock) -> Result<Self, WatchdogError> {
+        let settings = WatchdogSettings {
+            enable: false,
+            allow_updates: true,
+            ..Default::default()
+        };
+        let watchdog = Watchdog::init(wdog, settings)?;
+
+        if wdog.cs.read().en().is_1() {
+            Err(WatchdogError::ConfigurationFailed)
+        } else {
+            Ok(watchdog)
+        }
+    }
+
     pub fn reset(&self) {
         cortex_m::interrupt::free(|_cs| {
             self.register_block